@@ -0,0 +1,390 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt::Write;
+
+use either::*;
+use vm::{self, DecodedInstr, Instr, Reg, VmError, CONTEXT_SIZE};
+
+// An instruction at its address with the operands that follow it
+struct Decoded {
+      addr: usize,
+      instr: Option<Instr>, // None for a byte where an instruction was expected, which halts the VM
+      operands: Vec<u8>,
+      skip_to: Option<usize>, // where SKPZ and SKPNZ land, past the instruction after them
+}
+
+impl Decoded {
+      fn next(&self) -> usize {
+            self.addr + 1 + self.operands.len()
+      }
+
+      // Statically known branch target
+      fn target(&self) -> Option<usize> {
+            match self.instr {
+                  Some(Instr::BRZ) | Some(Instr::BRN) | Some(Instr::BRO)
+                  | Some(Instr::BRLE) | Some(Instr::BRGT) | Some(Instr::JMP)
+                  | Some(Instr::CALL) | Some(Instr::CBEQ) | Some(Instr::CBNE)
+                  | Some(Instr::BBS) | Some(Instr::BBC) => {
+                        self.operands.last().map(|addr| *addr as usize)
+                  }
+                  Some(Instr::BRZREL) | Some(Instr::BRNREL) | Some(Instr::BROREL)
+                  | Some(Instr::BRLEREL) | Some(Instr::BRGTREL) | Some(Instr::JMPREL) => {
+                        self.operands.last().and_then(|offset| self.next().checked_add_signed(*offset as i8 as isize))
+                  }
+                  Some(Instr::SKPZ) | Some(Instr::SKPNZ) => self.skip_to,
+                  Some(ref instr) if instr.is_wide() => match self.operands[..] {
+                        [high, low] => Some((high as usize) << 8 | low as usize),
+                        _ => None,
+                  },
+                  _ => None,
+            }
+      }
+
+      // Whether execution can continue with the next instruction
+      fn falls_through(&self) -> bool {
+            !matches!(self.instr, None | Some(Instr::JMP) | Some(Instr::JMPX) | Some(Instr::JMPREL) | Some(Instr::JMPW) | Some(Instr::RET)
+                                  | Some(Instr::HALT) | Some(Instr::HALTi))
+      }
+
+      fn ends_block(&self) -> bool {
+            self.target().is_some() || !self.falls_through()
+      }
+
+      // Registers the instruction may write, in any mode and on any path
+      fn writes(&self) -> Vec<Reg> {
+            let instr = match self.instr {
+                  Some(ref instr) => instr,
+                  None            => return Vec::new(),
+            };
+            let operand = |idx: usize| self.operands.get(idx).and_then(|code| Reg::from_code(*code));
+            match *instr {
+                  Instr::ADDA | Instr::ADDB | Instr::ADDX | Instr::ADDY
+                  | Instr::SUBA | Instr::SUBB | Instr::SUBX | Instr::SUBY
+                  | Instr::SETA | Instr::SETB | Instr::SETX | Instr::SETY
+                  | Instr::POPA | Instr::POPB | Instr::POPX | Instr::POPY
+                  | Instr::ADDAM | Instr::SUBAM => instr.register().into_iter().collect(),
+                  Instr::LDA | Instr::LDAX | Instr::LDAY
+                  | Instr::INA | Instr::RND | Instr::TSA | Instr::TFA
+                  | Instr::POPCNT | Instr::MUL => vec![Reg::A],
+                  Instr::DIV => vec![Reg::A, Reg::B],
+                  Instr::LOADCTX | Instr::ROTREGS => vec![Reg::A, Reg::B, Reg::X, Reg::Y],
+                  Instr::MOV | Instr::SWAPN | Instr::ANDLO | Instr::ANDHI
+                  | Instr::BSET | Instr::BCLR => operand(0).into_iter().collect(),
+                  Instr::XCHG => operand(0).into_iter().chain(operand(1)).collect(),
+                  _ => Vec::new(),
+            }
+      }
+}
+
+// Broad grouping of instructions for size reports
+#[derive(Debug, PartialEq, Eq, Hash, PartialOrd, Ord, Clone, Copy)]
+pub enum Category {
+      Stack,      // PUSH, POP and stack pointer transfers
+      Register,   // SET, MOV and other moves between registers and flags
+      Arithmetic, // ADD, SUB, CMP and bit manipulation
+      Memory,     // loads, stores and block operations
+      Control,    // branches, jumps, calls and halts
+      Io,         // input, output and the random number generator
+}
+
+fn category(instr: &Instr) -> Category {
+      match *instr {
+            Instr::PUSHi | Instr::PUSHA | Instr::PUSHB | Instr::PUSHX | Instr::PUSHY
+            | Instr::POPA | Instr::POPB | Instr::POPX | Instr::POPY
+            | Instr::CMPSTK | Instr::TSA | Instr::TAS => Category::Stack,
+            Instr::SETA | Instr::SETB | Instr::SETX | Instr::SETY
+            | Instr::MOV | Instr::XCHG | Instr::ROTREGS | Instr::TFA | Instr::TAF => Category::Register,
+            Instr::ADDA | Instr::ADDB | Instr::ADDX | Instr::ADDY
+            | Instr::SUBA | Instr::SUBB | Instr::SUBX | Instr::SUBY
+            | Instr::CMPA | Instr::CMPB | Instr::CMPX | Instr::CMPY
+            | Instr::ADDAM | Instr::SUBAM | Instr::CMPAM
+            | Instr::SWAPN | Instr::ANDLO | Instr::ANDHI | Instr::BSET | Instr::BCLR
+            | Instr::POPCNT | Instr::PARITY | Instr::MUL | Instr::DIV => Category::Arithmetic,
+            Instr::LDA | Instr::STA | Instr::LDAX | Instr::LDAY | Instr::STAX | Instr::STAY
+            | Instr::FILL | Instr::CLRMEM | Instr::MEMCPY
+            | Instr::SAVECTX | Instr::LOADCTX | Instr::STI => Category::Memory,
+            Instr::BRZ | Instr::BRN | Instr::BRO | Instr::BRLE | Instr::BRGT
+            | Instr::JMP | Instr::JMPX | Instr::CALL | Instr::RET
+            | Instr::CBEQ | Instr::CBNE | Instr::BBS | Instr::BBC | Instr::HALT | Instr::HALTi | Instr::NOP
+            | Instr::SKPZ | Instr::SKPNZ
+            | Instr::BRZREL | Instr::BRNREL | Instr::BROREL
+            | Instr::BRLEREL | Instr::BRGTREL | Instr::JMPREL
+            | Instr::BRZW | Instr::BRNW | Instr::BROW
+            | Instr::BRLEW | Instr::BRGTW | Instr::JMPW => Category::Control,
+            Instr::OUTA | Instr::INA | Instr::RND => Category::Io,
+      }
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct ProgramStats {
+      pub instructions: usize,
+      pub size: usize, // encoded bytes, one per program entry
+      pub categories: BTreeMap<Category, usize>,
+      pub max_address: Option<usize>, // highest memory address named by an operand
+}
+
+// Footprint of a program. Addresses reached through registers or the stack
+// are only known at run time and do not count towards `max_address`.
+pub fn program_stats(program: &[Either<u8, Instr>]) -> ProgramStats {
+      let mut stats = ProgramStats { instructions: 0, size: program.len(), categories: BTreeMap::new(), max_address: None };
+
+      for decoded in decode(program) {
+            let instr = match decoded.instr {
+                  Some(ref instr) => instr,
+                  None            => continue,
+            };
+            stats.instructions += 1;
+            *stats.categories.entry(category(instr)).or_insert(0) += 1;
+
+            let operand = |idx: usize| decoded.operands.get(idx).map(|x| *x as usize);
+            // Last address touched by a block of `len` bytes starting at `start`
+            let block = |start: Option<usize>, len: Option<usize>| match (start, len) {
+                  (Some(start), Some(len)) if len > 0 => Some(start + len - 1),
+                  _ => None,
+            };
+            let highest = match *instr {
+                  Instr::LDA | Instr::STA | Instr::STI | Instr::ADDAM | Instr::SUBAM | Instr::CMPAM
+                  | Instr::BBS | Instr::BBC => operand(0),
+                  Instr::SAVECTX | Instr::LOADCTX => block(operand(0), Some(CONTEXT_SIZE)),
+                  Instr::FILL | Instr::CLRMEM => block(operand(0), operand(1)),
+                  Instr::MEMCPY => block(operand(0), operand(2)).max(block(operand(1), operand(2))),
+                  _ => None,
+            };
+            stats.max_address = stats.max_address.max(highest);
+      }
+      stats
+}
+
+// Walks a VM program in execution order with `vm::decode_at`. Truncated
+// instructions keep the operands that are present.
+fn decode(program: &[Either<u8, Instr>]) -> Vec<Decoded> {
+      let entry = |addr: usize| &program[program.len() - 1 - addr];
+      let mut decoded = Vec::new();
+      let mut addr = 0;
+
+      while addr < program.len() {
+            let (instr, operands) = match vm::decode_at(program, addr) {
+                  Ok(DecodedInstr { instr, operands, .. }) => (Some(instr), operands),
+                  Err(VmError::ExpectedImmediate { pc: missing }) => {
+                        let operands = (addr + 1..missing).filter_map(|slot| entry(slot).clone().left()).collect();
+                        (entry(addr).clone().right(), operands)
+                  }
+                  Err(_) => (None, Vec::new()),
+            };
+            let next = Decoded { addr, instr, operands, skip_to: None };
+            addr = next.next();
+            decoded.push(next);
+      }
+
+      let after: Vec<usize> = decoded.iter().skip(1).map(Decoded::next).collect();
+      for (idx, instr) in decoded.iter_mut().enumerate() {
+            if matches!(instr.instr, Some(Instr::SKPZ) | Some(Instr::SKPNZ)) {
+                  instr.skip_to = Some(after.get(idx).cloned().unwrap_or(instr.next()));
+            }
+      }
+      decoded
+}
+
+// Graphviz DOT of the program's basic blocks. Blocks start at address 0, at
+// branch targets and after branches and halts. Fall-through edges are
+// unlabelled and branch edges are labelled `taken`; JMPX and RET targets are
+// only known at run time, so they have no edges.
+pub fn cfg_dot(program: &[Either<u8, Instr>]) -> String {
+      let decoded = decode(program);
+      let mut leaders: BTreeSet<usize> = decoded.first().map(|first| first.addr).into_iter().collect();
+      for instr in &decoded {
+            leaders.extend(instr.target());
+            if instr.ends_block() {
+                  leaders.insert(instr.next());
+            }
+      }
+
+      let mut blocks: BTreeMap<usize, Vec<&Decoded>> = BTreeMap::new();
+      let mut start = 0;
+      for instr in &decoded {
+            if leaders.contains(&instr.addr) {
+                  start = instr.addr;
+            }
+            blocks.entry(start).or_default().push(instr);
+      }
+
+      let mut dot = String::from("digraph cfg {\n");
+      for (start, instrs) in &blocks {
+            let lines: Vec<String> = instrs.iter().map(|decoded| {
+                  let operands: Vec<String> = decoded.operands.iter().map(|x| x.to_string()).collect();
+                  match decoded.instr {
+                        Some(ref instr) => format!("{}: {:?} {}", decoded.addr, instr, operands.join(", ")).trim_end().to_string(),
+                        None            => format!("{}: .byte", decoded.addr),
+                  }
+            }).collect();
+            writeln!(dot, "      b{} [shape=box, label=\"{}\\l\"];", start, lines.join("\\l")).unwrap();
+
+            let last = instrs[instrs.len() - 1];
+            if last.falls_through() && blocks.contains_key(&last.next()) {
+                  writeln!(dot, "      b{} -> b{};", start, last.next()).unwrap();
+            }
+            if let Some(target) = last.target().filter(|target| blocks.contains_key(target)) {
+                  writeln!(dot, "      b{} -> b{} [label=\"taken\"];", start, target).unwrap();
+            }
+      }
+      dot.push_str("}\n");
+      dot
+}
+
+// Addresses of the instructions reachable from any of `roots` without
+// leaving through RET, JMPX or a halt. Both sides of every branch count, and
+// CALLs are followed into the routines they call.
+pub fn reachable(program: &[Either<u8, Instr>], roots: &[usize]) -> BTreeSet<usize> {
+      let decoded = decode(program);
+      let by_addr: BTreeMap<usize, &Decoded> = decoded.iter().map(|instr| (instr.addr, instr)).collect();
+      let mut seen = BTreeSet::new();
+      let mut pending = roots.to_vec();
+
+      while let Some(addr) = pending.pop() {
+            let instr = match by_addr.get(&addr) {
+                  Some(instr) if instr.instr.is_some() && seen.insert(addr) => instr,
+                  _ => continue,
+            };
+            pending.extend(instr.target());
+            if instr.falls_through() {
+                  pending.push(instr.next());
+            }
+      }
+      seen
+}
+
+// Registers written by any instruction `reachable` from `entry`
+pub fn clobbered_registers(program: &[Either<u8, Instr>], entry: usize) -> BTreeSet<Reg> {
+      let reached = reachable(program, &[entry]);
+      decode(program).iter().filter(|instr| reached.contains(&instr.addr)).flat_map(Decoded::writes).collect()
+}
+
+// Delta debugs a program `fails` holds for: whole instructions are removed,
+// in halving chunks down to one at a time, for as long as `fails` still holds
+// on what remains. Branch targets are not adjusted, so the result is only
+// meaningful through `fails` itself.
+pub fn minimize<F: Fn(&[Either<u8, Instr>]) -> bool>(program: &[Either<u8, Instr>], fails: F) -> Vec<Either<u8, Instr>> {
+      let in_order: Vec<Either<u8, Instr>> = program.iter().rev().cloned().collect();
+      let mut units: Vec<&[Either<u8, Instr>]> = decode(program).iter().map(|decoded| &in_order[decoded.addr..decoded.next()]).collect();
+      let join = |units: &[&[Either<u8, Instr>]]| -> Vec<Either<u8, Instr>> { units.iter().rev().flat_map(|unit| unit.iter().rev().cloned()).collect() };
+
+      let mut chunk = (units.len() / 2).max(1);
+      loop {
+            let mut removed = false;
+            let mut start = 0;
+            while start < units.len() {
+                  let end = (start + chunk).min(units.len());
+                  let candidate: Vec<&[Either<u8, Instr>]> = units[..start].iter().chain(&units[end..]).cloned().collect();
+                  if fails(&join(&candidate)) {
+                        units = candidate;
+                        removed = true;
+                  } else {
+                        start = end;
+                  }
+            }
+            match (removed, chunk) {
+                  (true, _)  => (),
+                  (false, 1) => break,
+                  (false, _) => chunk /= 2,
+            }
+      }
+      join(&units)
+}
+
+#[cfg(test)]
+mod tests {
+      use super::*;
+      use assembler::{assemble, assemble_with_symbols};
+      use program::Program;
+      use vm::{VmError, VM};
+
+      #[test]
+      fn control_flow_graph_of_a_loop() {
+            let program = assemble("SETA #3\n\
+                                    loop: SUBA #1\n\
+                                    BRZ done\n\
+                                    JMP loop\n\
+                                    done: HALT").unwrap();
+            let dot = cfg_dot(&program);
+
+            assert!(dot.starts_with("digraph cfg {\n"));
+            assert_eq!(dot.matches("[shape=box").count(), 4);
+            assert!(dot.contains("      b2 [shape=box, label=\"2: SUBA 1\\l4: BRZ 8\\l\"];\n"));
+            assert!(dot.contains("      b0 -> b2;\n"));
+            assert!(dot.contains("      b2 -> b6;\n"));
+            assert!(dot.contains("      b2 -> b8 [label=\"taken\"];\n"));
+            // The back edge closing the loop, JMP has no fall-through
+            assert!(dot.contains("      b6 -> b2 [label=\"taken\"];\n"));
+            assert!(!dot.contains("b6 -> b8"));
+            assert_eq!(dot.matches("->").count(), 4);
+      }
+
+      #[test]
+      fn decoding_wide_and_truncated_instructions() {
+            let program = assemble("JMPW 0x0004\n.byte 7\nHALT").unwrap();
+            assert_eq!(reachable(&program, &[0]), vec![0, 4].into_iter().collect());
+
+            let truncated = vec![Left(1), Right(Instr::MOV)];
+            assert_eq!(program_stats(&truncated).instructions, 1);
+            assert!(cfg_dot(&truncated).contains("label=\"0: MOV 1\\l\""));
+      }
+
+      #[test]
+      fn stats_of_a_program() {
+            let program = assemble("SETA #1\n\
+                                    loop: STA 0x10\n\
+                                    PUSHA\n\
+                                    MEMCPY 0x10, 0x40, #4\n\
+                                    SAVECTX 0x20\n\
+                                    ADDA 0x30\n\
+                                    OUTA\n\
+                                    BRZ loop\n\
+                                    HALT").unwrap();
+            let stats = program_stats(&program);
+
+            assert_eq!(stats.instructions, 9);
+            assert_eq!(stats.size, 17);
+            assert_eq!(stats.max_address, Some(0x43));
+            let categories: Vec<(Category, usize)> = stats.categories.into_iter().collect();
+            assert_eq!(categories, vec![(Category::Stack, 1), (Category::Register, 1), (Category::Arithmetic, 1),
+                                        (Category::Memory, 3), (Category::Control, 2), (Category::Io, 1)]);
+
+            let empty = program_stats(&assemble("PUSHA\nFILL 0x80, #0").unwrap());
+            assert_eq!((empty.instructions, empty.max_address), (2, None));
+      }
+
+      #[test]
+      fn registers_clobbered_by_a_routine() {
+            let (program, symbols) = assemble_with_symbols("CALL clear\n\
+                                                            HALT\n\
+                                                            clear: SETX #0\n\
+                                                            next: CMPX #8\n\
+                                                            BRZ done\n\
+                                                            SETA #0\n\
+                                                            PUSHB\n\
+                                                            ADDX #1\n\
+                                                            JMP next\n\
+                                                            done: RET\n\
+                                                            MOV Y, B").unwrap();
+            let expected: BTreeSet<Reg> = [Reg::A, Reg::X].iter().cloned().collect();
+            assert_eq!(clobbered_registers(&program, symbols["clear"]), expected);
+            assert_eq!(clobbered_registers(&program, 0), expected);
+            assert_eq!(clobbered_registers(&program, symbols["done"]), BTreeSet::new());
+
+            let (program, symbols) = assemble_with_symbols("MOV B, A\n.include \"mul\"").unwrap();
+            let expected: BTreeSet<Reg> = [Reg::A, Reg::B, Reg::X].iter().cloned().collect();
+            assert_eq!(clobbered_registers(&program, symbols["mul"]), expected);
+      }
+
+      #[test]
+      fn minimizing_a_failing_program() {
+            let underflows = |program: &[Either<u8, Instr>]| match VM::new(program.to_vec()).execute() {
+                  Err(error) => error.cause() == &VmError::StackUnderflow,
+                  Ok(_)      => false,
+            };
+            let program = Program::new().set_a(1).set_b(2).add_x(3).mov(0, 1).pop_a().sta(0x10).halt().build();
+            assert!(underflows(&program));
+
+            assert_eq!(minimize(&program, underflows), vec![Right(Instr::POPA)]);
+            assert_eq!(minimize(&program, |_| true), vec![]);
+      }
+}
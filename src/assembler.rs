@@ -0,0 +1,1128 @@
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{BTreeMap, HashMap};
+use std::error;
+use std::hash::{Hash, Hasher};
+use std::fmt;
+use std::fmt::Write;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use either::*;
+use analysis;
+use bytecode::{self, OPCODES};
+use stdlib;
+use vm::{self, DecodedInstr, Instr, Registers, VmError};
+
+// Resolved address of every label, including those from included files
+pub type SymbolTable = BTreeMap<String, usize>;
+
+// Extra mnemonics and the built-in mnemonic each one stands for, such as
+// `LOAD` for `LDA`. Both sides are case insensitive like other mnemonics.
+pub type Aliases = BTreeMap<String, String>;
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct AssembleError {
+      pub line: usize, // 0 for errors not tied to a line of source
+      pub column: usize, // 1-based, in bytes
+      pub message: String,
+      snippet: String,
+      width: usize,
+}
+
+impl fmt::Display for AssembleError {
+      fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            if self.line == 0 {
+                  return write!(f, "error: {}", self.message);
+            }
+            let gutter = self.line.to_string().len();
+            writeln!(f, "error: {}", self.message)?;
+            writeln!(f, "{:g$}--> line {}, column {}", "", self.line, self.column, g = gutter)?;
+            writeln!(f, "{:g$} |", "", g = gutter)?;
+            writeln!(f, "{} | {}", self.line, self.snippet)?;
+            write!(f, "{:g$} | {:c$}{}", "", "", "^".repeat(self.width), g = gutter, c = self.column - 1)
+      }
+}
+
+impl error::Error for AssembleError {}
+
+// Something suspicious in source that still assembled
+#[derive(Debug, PartialEq, Clone)]
+pub struct AssembleWarning {
+      pub line: usize,
+      pub message: String,
+}
+
+impl fmt::Display for AssembleWarning {
+      fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            write!(f, "warning: {}\n--> line {}", self.message, self.line)
+      }
+}
+
+pub type Warnings = Vec<AssembleWarning>;
+
+// Why `assemble_and_run` failed
+#[derive(Debug, PartialEq, Clone)]
+pub enum RunError {
+      Assemble(AssembleError),
+      Vm(VmError),
+}
+
+impl fmt::Display for RunError {
+      fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            match *self {
+                  RunError::Assemble(ref error) => write!(f, "{}", error),
+                  RunError::Vm(ref error)       => write!(f, "error: {}", error),
+            }
+      }
+}
+
+impl error::Error for RunError {}
+
+impl From<AssembleError> for RunError {
+      fn from(error: AssembleError) -> RunError {
+            RunError::Assemble(error)
+      }
+}
+
+impl From<VmError> for RunError {
+      fn from(error: VmError) -> RunError {
+            RunError::Vm(error)
+      }
+}
+
+// Every file an assembly included and the text read from it
+type Includes = Vec<(PathBuf, String)>;
+
+// A word of source text and the 1-based column it starts at
+#[derive(Debug, Clone)]
+struct Token {
+      text: String,
+      column: usize,
+}
+
+// A single source line after the first pass
+struct Statement {
+      line: usize,
+      source: String,
+      addr: usize,
+      instr: Option<Instr>,
+      operands: Vec<Token>,
+      lowered: Vec<Either<u8, Instr>>, // entries a pseudo-instruction expanded to
+}
+
+// A single source line after both assembler passes
+struct Assembled {
+      line: usize,
+      source: String,
+      addr: usize,
+      code: bool, // holds instructions rather than `.byte` data
+      entries: Vec<Either<u8, Instr>>, // in execution order
+}
+
+fn error(line: usize, source: &str, column: usize, width: usize, message: String) -> AssembleError {
+      AssembleError {
+            line,
+            column,
+            message,
+            snippet: source.replace('\t', " "),
+            width: width.max(1),
+      }
+}
+
+// An error about the source as a whole, with no line to point at
+fn unlocated_error(message: String) -> AssembleError {
+      AssembleError { line: 0, column: 0, message, snippet: String::new(), width: 0 }
+}
+
+fn token_error(stmt_line: usize, source: &str, token: &Token, message: String) -> AssembleError {
+      error(stmt_line, source, token.column, token.text.len(), message)
+}
+
+fn parse_mnemonic(name: &str) -> Option<Instr> {
+      OPCODES.iter()
+             .find(|instr| format!("{:?}", instr).eq_ignore_ascii_case(name))
+             .cloned()
+}
+
+// Immediates are written `#value`, bare numbers and labels are addresses
+#[derive(Debug, PartialEq, Clone, Copy)]
+enum Mode {
+      Immediate,
+      Address,
+      Register,
+}
+
+impl Mode {
+      fn describe(self) -> &'static str {
+            match self {
+                  Mode::Immediate => "an immediate `#value`",
+                  Mode::Address   => "an address",
+                  Mode::Register  => "a register",
+            }
+      }
+}
+
+fn modes(instr: &Instr) -> &'static [Mode] {
+      match *instr {
+            Instr::PUSHi
+            | Instr::ADDA | Instr::ADDB | Instr::ADDX | Instr::ADDY
+            | Instr::SUBA | Instr::SUBB | Instr::SUBX | Instr::SUBY
+            | Instr::SETA | Instr::SETB | Instr::SETX | Instr::SETY
+            | Instr::CMPA | Instr::CMPB | Instr::CMPX | Instr::CMPY
+            | Instr::HALTi
+            | Instr::BRZREL | Instr::BRNREL | Instr::BROREL
+            | Instr::BRLEREL | Instr::BRGTREL | Instr::JMPREL
+            | Instr::ROTREGS => &[Mode::Immediate],
+            Instr::FILL | Instr::CLRMEM | Instr::STI => &[Mode::Address, Mode::Immediate],
+            Instr::MEMCPY => &[Mode::Address, Mode::Address, Mode::Immediate],
+            Instr::MOV | Instr::XCHG => &[Mode::Register, Mode::Register],
+            Instr::SWAPN | Instr::ANDLO | Instr::ANDHI => &[Mode::Register],
+            Instr::BSET | Instr::BCLR => &[Mode::Register, Mode::Immediate],
+            Instr::CBEQ | Instr::CBNE => &[Mode::Register, Mode::Immediate, Mode::Address],
+            Instr::BBS | Instr::BBC => &[Mode::Address, Mode::Immediate, Mode::Address],
+            _ => &[Mode::Address],
+      }
+}
+
+// Register codes may also be written as bare numbers
+fn operand_mode(text: &str) -> Mode {
+      if text.starts_with('#') {
+            Mode::Immediate
+      } else if register_code(text).is_some() {
+            Mode::Register
+      } else {
+            Mode::Address
+      }
+}
+
+// `ADDA 0x40` style address operands select the memory operand opcode
+fn direct(instr: &Instr) -> Option<Instr> {
+      match *instr {
+            Instr::ADDA => Some(Instr::ADDAM),
+            Instr::SUBA => Some(Instr::SUBAM),
+            Instr::CMPA => Some(Instr::CMPAM),
+            _           => None,
+      }
+}
+
+// Branches written with an address while `.pic` is active assemble to the
+// relative opcode, with the offset to the address as their operand
+fn relative(instr: &Instr) -> Option<Instr> {
+      match *instr {
+            Instr::BRZ  => Some(Instr::BRZREL),
+            Instr::BRN  => Some(Instr::BRNREL),
+            Instr::BRO  => Some(Instr::BROREL),
+            Instr::BRLE => Some(Instr::BRLEREL),
+            Instr::BRGT => Some(Instr::BRGTREL),
+            Instr::JMP  => Some(Instr::JMPREL),
+            _           => None,
+      }
+}
+
+// `LDA (X)` style pointer operands select the register indirect opcode
+fn indirect(instr: &Instr, operand: &str) -> Option<Instr> {
+      match (instr, operand.to_ascii_uppercase().as_str()) {
+            (&Instr::LDA, "(X)") => Some(Instr::LDAX),
+            (&Instr::LDA, "(Y)") => Some(Instr::LDAY),
+            (&Instr::STA, "(X)") => Some(Instr::STAX),
+            (&Instr::STA, "(Y)") => Some(Instr::STAY),
+            _                    => None,
+      }
+}
+
+// Register names are reserved and assemble to their register operand code
+fn register_code(name: &str) -> Option<usize> {
+      match name.to_ascii_uppercase().as_str() {
+            "A" => Some(0),
+            "B" => Some(1),
+            "X" => Some(2),
+            "Y" => Some(3),
+            _   => None,
+      }
+}
+
+pub(crate) fn parse_number(token: &str) -> Option<usize> {
+      if token.starts_with("0x") || token.starts_with("0X") {
+            usize::from_str_radix(&token[2..], 16).ok()
+      } else {
+            token.parse().ok()
+      }
+}
+
+fn is_label(name: &str) -> bool {
+      let mut chars = name.chars();
+      match chars.next() {
+            Some(c) if c.is_ascii_alphabetic() || c == '_' => {
+                  chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+            }
+            _ => false,
+      }
+}
+
+// Splits on whitespace and commas, `offset` is the byte offset of `code` in its line
+fn tokenize(code: &str, offset: usize) -> Vec<Token> {
+      let mut tokens = Vec::new();
+      let mut start = None;
+
+      for (idx, c) in code.char_indices().chain(Some((code.len(), ' '))) {
+            let separator = c.is_whitespace() || c == ',';
+            match start {
+                  Some(from) if separator => {
+                        tokens.push(Token { text: code[from..idx].to_string(), column: offset + from + 1 });
+                        start = None;
+                  }
+                  None if !separator => start = Some(idx),
+                  _ => (),
+            }
+      }
+      tokens
+}
+
+// First pass: split lines into labels, mnemonics and operands, assigning addresses
+struct Parser {
+      statements: Vec<Statement>,
+      labels: SymbolTable,
+      addr: usize,
+      includes: Vec<String>, // names or paths currently being included, to catch cycles
+      dir: PathBuf,          // directory relative includes resolve against
+      files: Includes,
+      pic: bool,             // a `.pic` directive has been seen
+      aliases: Aliases,      // keyed by upper case alias
+}
+
+impl Parser {
+      fn new(dir: PathBuf) -> Parser {
+            Parser {
+                  statements: Vec::new(),
+                  labels: SymbolTable::new(),
+                  addr: 0,
+                  includes: Vec::new(),
+                  dir,
+                  files: Vec::new(),
+                  pic: false,
+                  aliases: Aliases::new(),
+            }
+      }
+
+      fn parse(&mut self, src: &str) -> Result<(), AssembleError> {
+            for (idx, source) in src.lines().enumerate() {
+                  self.parse_line(idx + 1, source)?;
+            }
+            Ok(())
+      }
+
+      fn parse_line(&mut self, line: usize, source: &str) -> Result<(), AssembleError> {
+            let mut code = match source.find(';') {
+                  Some(pos) => &source[..pos],
+                  None      => source,
+            };
+            let mut offset = 0;
+
+            if let Some(pos) = code.find(':') {
+                  let label = code[..pos].trim();
+                  let column = code.find(label).unwrap_or(0) + 1;
+                  if !is_label(label) || register_code(label).is_some() {
+                        return Err(error(line, source, column, label.len(), format!("invalid label `{}`", label)));
+                  }
+                  if self.labels.insert(label.to_string(), self.addr).is_some() {
+                        return Err(error(line, source, column, label.len(), format!("duplicate label `{}`", label)));
+                  }
+                  code = &code[pos + 1..];
+                  offset = pos + 1;
+            }
+
+            let mut tokens = tokenize(code, offset).into_iter();
+            let mnemonic = match tokens.next() {
+                  Some(token) => match self.aliases.get(&token.text.to_ascii_uppercase()) {
+                        Some(canonical) => Token { text: canonical.clone(), column: token.column },
+                        None            => token,
+                  },
+                  None => {
+                        self.push(line, source, None, Vec::new());
+                        return Ok(());
+                  }
+            };
+            let operands: Vec<Token> = tokens.collect();
+
+            if mnemonic.text.starts_with('.') {
+                  return self.directive(line, source, &mnemonic, operands);
+            }
+
+            if mnemonic.text.eq_ignore_ascii_case("SETAB") {
+                  return self.set_pair(line, source, &mnemonic, &operands);
+            }
+
+            let mut instr = match parse_mnemonic(&mnemonic.text) {
+                  Some(instr) => instr,
+                  None => return Err(token_error(line, source, &mnemonic,
+                                                 format!("unknown mnemonic `{}`", mnemonic.text))),
+            };
+            let mut operands = operands;
+
+            if operands.len() == 1 {
+                  if let Some(pointer) = indirect(&instr, &operands[0].text) {
+                        instr = pointer;
+                        operands.clear();
+                  } else if instr == Instr::HALT {
+                        instr = Instr::HALTi;
+                  } else if operand_mode(&operands[0].text) == Mode::Address {
+                        instr = direct(&instr).unwrap_or(instr);
+                  }
+            }
+
+            // A wide address is written as one operand and stored as two
+            let expected = if instr.is_wide() { 1 } else { instr.arity() };
+            if operands.len() != expected {
+                  let span = operands.get(expected).unwrap_or(&mnemonic);
+                  return Err(token_error(line, source, span, format!("{:?} expects {} operand(s), found {}",
+                                                                      instr, expected, operands.len())));
+            }
+
+            for (operand, &mode) in operands.iter().zip(modes(&instr)) {
+                  let found = operand_mode(&operand.text);
+                  let allowed = found == mode || (mode == Mode::Register && found == Mode::Address);
+                  if !allowed {
+                        return Err(token_error(line, source, operand, format!("{} expects {}, found `{}`",
+                                                                           mnemonic.text.to_ascii_uppercase(),
+                                                                           mode.describe(), operand.text)));
+                  }
+            }
+
+            if self.pic {
+                  instr = relative(&instr).unwrap_or(instr);
+            }
+            self.push(line, source, Some(instr), operands);
+            Ok(())
+      }
+
+      fn push(&mut self, line: usize, source: &str, instr: Option<Instr>, operands: Vec<Token>) {
+            let size = instr.as_ref().map_or(operands.len(), |instr| 1 + instr.arity());
+            self.statements.push(Statement { line, source: source.to_string(), addr: self.addr,
+                                             instr, operands, lowered: Vec::new() });
+            self.addr += size;
+      }
+
+      // `SETAB #0x1234` loads a 16-bit value big endian: A = 0x12, B = 0x34
+      fn set_pair(&mut self, line: usize, source: &str, mnemonic: &Token, operands: &[Token]) -> Result<(), AssembleError> {
+            let operand = match operands {
+                  [operand] => operand,
+                  _ => return Err(token_error(line, source, operands.get(1).unwrap_or(mnemonic),
+                                              format!("SETAB expects 1 operand(s), found {}", operands.len()))),
+            };
+            let value = match operand.text.strip_prefix('#').and_then(parse_number) {
+                  Some(value) if value <= 0xFFFF => value,
+                  _ => return Err(token_error(line, source, operand,
+                                              format!("`{}` is not a 16-bit immediate", operand.text))),
+            };
+
+            let lowered = vec![Right(Instr::SETA), Left((value >> 8) as u8), Right(Instr::SETB), Left(value as u8)];
+            self.statements.push(Statement { line, source: source.to_string(), addr: self.addr,
+                                             instr: None, operands: Vec::new(), lowered });
+            self.addr += 4;
+            Ok(())
+      }
+
+      fn directive(&mut self, line: usize, source: &str, name: &Token, operands: Vec<Token>) -> Result<(), AssembleError> {
+            match name.text.as_str() {
+                  // Raw operand bytes, for data or entries that are not instructions
+                  ".byte" => {
+                        if operands.is_empty() {
+                              return Err(token_error(line, source, name, ".byte expects at least one value".to_string()));
+                        }
+                        self.push(line, source, None, operands);
+                        Ok(())
+                  }
+                  // Position independent code from here on, see `relative`
+                  ".pic" => {
+                        if let Some(extra) = operands.first() {
+                              return Err(token_error(line, source, extra, ".pic takes no operands".to_string()));
+                        }
+                        self.pic = true;
+                        self.push(line, source, None, Vec::new());
+                        Ok(())
+                  }
+                  // Zero bytes up to the address `.org` names, or up to the next
+                  // multiple of the `.align` operand
+                  ".org" | ".align" => {
+                        let value = match &operands[..] {
+                              [operand] => parse_number(&operand.text).map(|value| (operand, value)),
+                              _         => None,
+                        };
+                        let target = match value {
+                              Some((_, addr)) if name.text == ".org" => addr,
+                              Some((_, n)) if n > 0 => self.addr.div_ceil(n) * n,
+                              _ => return Err(token_error(line, source, name, format!("{} expects a number", name.text))),
+                        };
+                        if target < self.addr {
+                              let operand = &operands[0];
+                              return Err(token_error(line, source, operand, format!(".org {} is behind the current address {}",
+                                                                                  operand.text, self.addr)));
+                        }
+                        let lowered = vec![Left(0); target - self.addr];
+                        self.statements.push(Statement { line, source: source.to_string(), addr: self.addr,
+                                                         instr: None, operands: Vec::new(), lowered });
+                        self.addr = target;
+                        Ok(())
+                  }
+                  ".include" => {
+                        self.push(line, source, None, Vec::new());
+                        let target = match &operands[..] {
+                              [target] if target.text.len() >= 2
+                                          && target.text.starts_with('"')
+                                          && target.text.ends_with('"') => target,
+                              _ => return Err(token_error(line, source, name,
+                                                          ".include expects a quoted name".to_string())),
+                        };
+                        self.include(line, source, target)
+                  }
+                  _ => Err(token_error(line, source, name, format!("unknown directive `{}`", name.text))),
+            }
+      }
+
+      // Built-in library names take precedence over files
+      fn include(&mut self, line: usize, source: &str, target: &Token) -> Result<(), AssembleError> {
+            let name = &target.text[1..target.text.len() - 1];
+            if let Some(text) = stdlib::library(name) {
+                  return self.include_text(line, source, target, name.to_string(), text, None);
+            }
+
+            let path = self.dir.join(name);
+            let text = match fs::read_to_string(&path) {
+                  Ok(text) => text,
+                  Err(err) => return Err(token_error(line, source, target,
+                                                     format!("cannot include `{}`: {}", name, err))),
+            };
+            let key = fs::canonicalize(&path).unwrap_or_else(|_| path.clone());
+            let dir = key.parent().map(Path::to_path_buf);
+            self.files.push((path, text.clone()));
+            self.include_text(line, source, target, key.display().to_string(), &text, dir)
+      }
+
+      fn include_text(&mut self, line: usize, source: &str, target: &Token, key: String,
+                      text: &str, dir: Option<PathBuf>) -> Result<(), AssembleError> {
+            if self.includes.contains(&key) {
+                  let name = &target.text[1..target.text.len() - 1];
+                  return Err(token_error(line, source, target, format!("`{}` includes itself", name)));
+            }
+
+            self.includes.push(key);
+            let outer = dir.map(|dir| ::std::mem::replace(&mut self.dir, dir));
+            let result = self.parse(text);
+            if let Some(outer) = outer {
+                  self.dir = outer;
+            }
+            self.includes.pop();
+            result
+      }
+}
+
+fn assemble_lines(src: &str, dir: PathBuf) -> Result<(Vec<Assembled>, SymbolTable, Includes), AssembleError> {
+      assemble_parsed(Parser::new(dir), src)
+}
+
+// Second pass: resolve operands against the label table
+fn assemble_parsed(mut parser: Parser, src: &str) -> Result<(Vec<Assembled>, SymbolTable, Includes), AssembleError> {
+      parser.parse(src)?;
+      let labels = parser.labels;
+      let files = parser.files;
+      let mut lines = Vec::with_capacity(parser.statements.len());
+
+      for stmt in parser.statements {
+            let code = stmt.instr.is_some() || stmt.lowered.iter().any(Either::is_right);
+            // Relative branches only take addresses under `.pic`
+            let wide = stmt.instr.as_ref().is_some_and(Instr::is_wide);
+            let pic = matches!(stmt.instr, Some(Instr::BRZREL) | Some(Instr::BRNREL) | Some(Instr::BROREL)
+                                           | Some(Instr::BRLEREL) | Some(Instr::BRGTREL) | Some(Instr::JMPREL));
+            let mut entries = Vec::new();
+            entries.extend(stmt.lowered);
+            entries.extend(stmt.instr.map(Right));
+            for operand in &stmt.operands {
+                  // `#-3` is the two's complement byte of a signed immediate
+                  if let Some(digits) = operand.text.strip_prefix("#-") {
+                        match parse_number(digits) {
+                              Some(n) if n <= 128 => entries.push(Left((256 - n) as u8)),
+                              Some(_) => return Err(token_error(stmt.line, &stmt.source, operand,
+                                                                format!("immediate `{}` is out of range -128..=127", operand.text))),
+                              None => return Err(token_error(stmt.line, &stmt.source, operand,
+                                                             format!("malformed operand `{}`", operand.text))),
+                        }
+                        continue;
+                  }
+                  let (text, register) = match operand.text.strip_prefix('#') {
+                        Some(text) => (text, None),
+                        None       => (operand.text.as_str(), register_code(&operand.text)),
+                  };
+                  let value = match parse_number(text).or(register) {
+                        Some(value) => value,
+                        None => match labels.get(text) {
+                              Some(addr) => *addr,
+                              None if is_label(text) => {
+                                    return Err(token_error(stmt.line, &stmt.source, operand,
+                                                           format!("undefined label `{}`", operand.text)));
+                              }
+                              None => return Err(token_error(stmt.line, &stmt.source, operand,
+                                                             format!("malformed operand `{}`", operand.text))),
+                        },
+                  };
+                  if pic && !operand.text.starts_with('#') {
+                        let offset = value as isize - (stmt.addr + 2) as isize;
+                        if !(-128..=127).contains(&offset) {
+                              return Err(token_error(stmt.line, &stmt.source, operand,
+                                                     format!("`{}` is too far for a relative branch", operand.text)));
+                        }
+                        entries.push(Left(offset as u8));
+                        continue;
+                  }
+                  if wide {
+                        if value > 0xFFFF {
+                              return Err(token_error(stmt.line, &stmt.source, operand,
+                                                     format!("operand `{}` does not fit in 16 bits", operand.text)));
+                        }
+                        entries.push(Left((value >> 8) as u8));
+                        entries.push(Left(value as u8));
+                        continue;
+                  }
+                  if value > 255 {
+                        return Err(token_error(stmt.line, &stmt.source, operand,
+                                               format!("operand `{}` does not fit in a byte", operand.text)));
+                  }
+                  entries.push(Left(value as u8));
+            }
+            lines.push(Assembled { line: stmt.line, source: stmt.source, addr: stmt.addr, code, entries });
+      }
+
+      Ok((lines, labels, files))
+}
+
+// Renders a program as source, one instruction per line. Well formed programs
+// assemble back to the same entries; stray operands are emitted as `.byte`.
+pub fn disassemble(program: &[Either<u8, Instr>]) -> String {
+      let mut src = String::new();
+      let mut pc = 0;
+
+      while pc < program.len() {
+            if let Ok(DecodedInstr { instr, operands, .. }) = vm::decode_at(program, pc) {
+                  let rendered: Vec<String> = match operands[..] {
+                        [high, low] if instr.is_wide() => vec![((high as usize) << 8 | low as usize).to_string()],
+                        _ => operands.iter().zip(modes(&instr)).map(|(x, &mode)| {
+                              let prefix = if mode == Mode::Immediate { "#" } else { "" };
+                              format!("{}{}", prefix, x)
+                        }).collect(),
+                  };
+                  writeln!(src, "{:?} {}", instr, rendered.join(", ")).unwrap();
+                  src.truncate(src.trim_end().len());
+                  src.push('\n');
+                  pc += 1 + operands.len();
+                  continue;
+            }
+
+            // A stray byte, or a truncated instruction whose opcode is kept as a
+            // raw byte along with the operands that are present
+            let mut bytes = vec![bytecode::encode_entry(&program[program.len() - 1 - pc])];
+            pc += 1;
+            if let Right(ref instr) = program[program.len() - pc] {
+                  while bytes.len() <= instr.arity() && pc < program.len() {
+                        match program[program.len() - 1 - pc] {
+                              Left(x) => bytes.push(x),
+                              Right(_) => break,
+                        }
+                        pc += 1;
+                  }
+            }
+            let bytes: Vec<String> = bytes.iter().map(|x| x.to_string()).collect();
+            writeln!(src, ".byte {}", bytes.join(", ")).unwrap();
+      }
+      src
+}
+
+// Relative `.include` paths resolve against the working directory
+pub fn assemble(src: &str) -> Result<Vec<Either<u8, Instr>>, AssembleError> {
+      assemble_in(src, PathBuf::new()).map(|(program, _)| program)
+}
+
+pub fn assemble_with_symbols(src: &str) -> Result<(Vec<Either<u8, Instr>>, SymbolTable), AssembleError> {
+      assemble_in(src, PathBuf::new())
+}
+
+// Assembles a source file, resolving its includes relative to the file itself
+pub fn assemble_file<P: AsRef<Path>>(path: P) -> Result<Vec<Either<u8, Instr>>, AssembleError> {
+      let path = path.as_ref();
+      let src = match fs::read_to_string(path) {
+            Ok(src) => src,
+            Err(err) => return Err(unlocated_error(format!("cannot read `{}`: {}", path.display(), err))),
+      };
+      let dir = path.parent().map(Path::to_path_buf).unwrap_or_default();
+      assemble_in(&src, dir).map(|(program, _)| program)
+}
+
+// Assembles the source and warns about every line of instructions no path
+// from address 0 or from a label reaches, such as code after a HALT or JMP.
+// `.byte` data is never reported.
+pub fn assemble_with_warnings(src: &str) -> Result<(Vec<Either<u8, Instr>>, Warnings), AssembleError> {
+      let (lines, symbols, _) = assemble_lines(src, PathBuf::new())?;
+      let code: Vec<(usize, usize)> = lines.iter().filter(|line| line.code).map(|line| (line.line, line.addr)).collect();
+      let program = flatten(lines);
+
+      let roots: Vec<usize> = Some(0).into_iter().chain(symbols.values().cloned()).collect();
+      let reached = analysis::reachable(&program, &roots);
+      let warnings = code.into_iter()
+                         .filter(|(_, addr)| !reached.contains(addr))
+                         .map(|(line, _)| AssembleWarning { line, message: "unreachable code".to_string() })
+                         .collect();
+      Ok((program, warnings))
+}
+
+// Assembles source written with extra mnemonics. An alias must be a valid
+// label name that is neither a built-in mnemonic nor another alias in a
+// different case, and must stand for a built-in mnemonic.
+pub fn assemble_with_aliases(src: &str, aliases: &Aliases) -> Result<Vec<Either<u8, Instr>>, AssembleError> {
+      let builtin = |name: &str| parse_mnemonic(name).is_some() || name.eq_ignore_ascii_case("SETAB");
+      let mut parser = Parser::new(PathBuf::new());
+      for (alias, canonical) in aliases {
+            if !is_label(alias) {
+                  return Err(unlocated_error(format!("alias `{}` is not a valid name", alias)));
+            }
+            if let Some(other) = aliases.keys().find(|other| *other != alias && other.eq_ignore_ascii_case(alias)) {
+                  return Err(unlocated_error(format!("aliases `{}` and `{}` differ only in case", other, alias)));
+            }
+            if builtin(alias) {
+                  return Err(unlocated_error(format!("alias `{}` clashes with a built-in mnemonic", alias)));
+            }
+            if !builtin(canonical) {
+                  return Err(unlocated_error(format!("alias `{}` stands for unknown mnemonic `{}`", alias, canonical)));
+            }
+            parser.aliases.insert(alias.to_ascii_uppercase(), canonical.clone());
+      }
+      let (lines, _, _) = assemble_parsed(parser, src)?;
+      Ok(flatten(lines))
+}
+
+// Assembles the source and runs it to completion, returning the final registers
+pub fn assemble_and_run(src: &str) -> Result<Registers, RunError> {
+      Ok(vm::run(assemble(src)?)?)
+}
+
+// Assembles a fixed size image, padding after the program with zero bytes
+// until it is `size` entries long. `.org` and `.align` padding inside the
+// source counts towards the size.
+pub fn assemble_padded(src: &str, size: usize) -> Result<Vec<Either<u8, Instr>>, AssembleError> {
+      let mut program = assemble(src)?;
+      if program.len() > size {
+            return Err(unlocated_error(format!("program is {} bytes, over the {} byte image", program.len(), size)));
+      }
+      let padding = vec![Left(0); size - program.len()];
+      program.splice(0..0, padding);
+      Ok(program)
+}
+
+fn assemble_in(src: &str, dir: PathBuf) -> Result<(Vec<Either<u8, Instr>>, SymbolTable), AssembleError> {
+      let (lines, symbols, _) = assemble_lines(src, dir)?;
+      Ok((flatten(lines), symbols))
+}
+
+fn flatten(lines: Vec<Assembled>) -> Vec<Either<u8, Instr>> {
+      let mut program: Vec<Either<u8, Instr>> = lines.into_iter()
+                                                     .flat_map(|line| line.entries)
+                                                     .collect();
+      program.reverse();
+      program
+}
+
+struct CacheEntry {
+      source: String,
+      program: Vec<Either<u8, Instr>>,
+      files: Includes,
+}
+
+// Memoizes `assemble` by source text. An entry is only reused while every
+// file it included still reads the same, errors are never cached.
+#[derive(Default)]
+pub struct AssemblerCache {
+      entries: HashMap<u64, CacheEntry>,
+      hits: usize,
+}
+
+impl AssemblerCache {
+      pub fn new() -> AssemblerCache {
+            AssemblerCache::default()
+      }
+
+      pub fn assemble(&mut self, src: &str) -> Result<Vec<Either<u8, Instr>>, AssembleError> {
+            let mut hasher = DefaultHasher::new();
+            src.hash(&mut hasher);
+            let key = hasher.finish();
+
+            if let Some(entry) = self.entries.get(&key) {
+                  let unchanged = entry.files.iter().all(|(path, text)| {
+                        fs::read_to_string(path).ok().as_ref() == Some(text)
+                  });
+                  if entry.source == src && unchanged {
+                        self.hits += 1;
+                        return Ok(entry.program.clone());
+                  }
+            }
+
+            let (lines, _, files) = assemble_lines(src, PathBuf::new())?;
+            let program = flatten(lines);
+            self.entries.insert(key, CacheEntry { source: src.to_string(), program: program.clone(), files });
+            Ok(program)
+      }
+
+      // Number of `assemble` calls answered from the cache
+      pub fn hits(&self) -> usize {
+            self.hits
+      }
+}
+
+// Assembles the source and renders a listing of `addr: bytes  source` per line
+pub fn assemble_listing(src: &str) -> Result<(Vec<Either<u8, Instr>>, String), AssembleError> {
+      let (lines, _, _) = assemble_lines(src, PathBuf::new())?;
+      let mut listing = String::new();
+      let mut program = Vec::new();
+
+      for line in lines {
+            let bytes: Vec<String> = line.entries
+                                         .iter()
+                                         .map(|entry| format!("{:02X}", bytecode::encode_entry(entry)))
+                                         .collect();
+            writeln!(listing, "{:04X}: {:<12}{}", line.addr, bytes.join(" "), line.source).unwrap();
+            program.extend(line.entries);
+      }
+
+      program.reverse();
+      Ok((program, listing))
+}
+
+#[cfg(test)]
+mod tests {
+      use super::*;
+      use testgen;
+      use vm::{ArithmeticMode, VM};
+
+      #[test]
+      fn assembling_programs() {
+            let src = "start: SETA #10   ; load A\n\
+                       \x20      adda #0x05\n\
+                       \x20      BRZ start\n\
+                       \x20      HALT\n";
+            let program = assemble(src).unwrap();
+            assert_eq!(program, vec![Right(Instr::HALT), Left(0), Right(Instr::BRZ),
+                                     Left(5), Right(Instr::ADDA), Left(10), Right(Instr::SETA)]);
+
+            let mut vm = VM::new(program);
+            vm.execute().unwrap();
+            assert_eq!(vm.A, 15);
+      }
+
+      #[test]
+      fn assembler_errors() {
+            assert_eq!(assemble("SETA #1\nFOO 2").unwrap_err().line, 2);
+            assert_eq!(assemble("SETA").unwrap_err().line, 1);
+            assert_eq!(assemble("SETA #256").unwrap_err().line, 1);
+            assert_eq!(assemble("BRZ nowhere").unwrap_err().line, 1);
+            assert_eq!(assemble("top: HALT\ntop: HALT").unwrap_err().line, 2);
+      }
+
+      #[test]
+      fn listing_shows_addresses_and_bytes() {
+            let src = "; countdown\n\
+                       \x20     SETA #3\n\
+                       loop: SUBA #1\n\
+                       \x20     BRZ done\n\
+                       done: HALT";
+            let (program, listing) = assemble_listing(src).unwrap();
+            assert_eq!(program, assemble(src).unwrap());
+
+            let lines: Vec<&str> = listing.lines().collect();
+            assert_eq!(lines.len(), 5);
+            assert_eq!(lines[0], "0000:             ; countdown");
+            assert_eq!(lines[1], "0000: 14 03             SETA #3");
+            assert_eq!(lines[2], "0002: 0D 01       loop: SUBA #1");
+            assert_eq!(lines[3], "0004: 11 06             BRZ done");
+            assert_eq!(lines[4], "0006: 18          done: HALT");
+      }
+
+      #[test]
+      fn errors_report_line_and_column() {
+            let err = assemble("SETA #1\n   FOO 2").unwrap_err();
+            assert_eq!((err.line, err.column), (2, 4));
+            assert_eq!(err.message, "unknown mnemonic `FOO`");
+
+            let err = assemble("SETA #1\nADDA #1\nloop: SUBA #1z").unwrap_err();
+            assert_eq!((err.line, err.column), (3, 12));
+            assert_eq!(err.to_string(), "error: malformed operand `#1z`\n \
+                                         --> line 3, column 12\n  \
+                                         |\n\
+                                         3 | loop: SUBA #1z\n  \
+                                         |            ^^^");
+
+            let err = assemble("HALT 1").unwrap_err();
+            assert_eq!((err.line, err.column), (1, 6));
+      }
+
+      #[test]
+      fn assembling_pointer_operands() {
+            let program = assemble("SETX #0x40\nLDA (X)\nsta (y)\nLDA 0x40").unwrap();
+            assert_eq!(program, vec![Left(0x40), Right(Instr::LDA), Right(Instr::STAY),
+                                     Right(Instr::LDAX), Left(0x40), Right(Instr::SETX)]);
+            assert_eq!(assemble("ADDA (X)").unwrap_err().column, 6);
+      }
+
+      #[test]
+      fn register_operands_and_includes() {
+            assert_eq!(assemble("MOV X, a").unwrap(), vec![Left(0), Left(2), Right(Instr::MOV)]);
+            assert_eq!(assemble("x: HALT").unwrap_err().message, "invalid label `x`");
+
+            assert_eq!(assemble(".include mul").unwrap_err().column, 1);
+            assert_eq!(assemble("  .include \"nope\"").unwrap_err().column, 12);
+            assert_eq!(assemble(".section 4").unwrap_err().message, "unknown directive `.section`");
+      }
+
+      #[test]
+      fn including_files() {
+            let dir = ::std::env::temp_dir().join(format!("nvm-include-{}", ::std::process::id()));
+            fs::create_dir_all(dir.join("lib")).unwrap();
+            fs::write(dir.join("main.asm"), "SETA #2\nCALL double\nHALT\n.include \"lib/double.asm\"\n").unwrap();
+            fs::write(dir.join("lib/double.asm"), ".include \"ret.asm\"\ndouble: ADDA #0\nJMP twice\n").unwrap();
+            fs::write(dir.join("lib/ret.asm"), "twice: MOV B, A\nSETA #4\nRET\n").unwrap();
+
+            let program = assemble_file(dir.join("main.asm")).unwrap();
+            let mut vm = VM::new(program);
+            vm.execute().unwrap();
+            assert_eq!((vm.A, vm.B), (4, 2));
+
+            fs::write(dir.join("lib/ret.asm"), ".include \"double.asm\"\n").unwrap();
+            let err = assemble_file(dir.join("main.asm")).unwrap_err();
+            assert_eq!(err.message, "`double.asm` includes itself");
+
+            fs::remove_dir_all(&dir).unwrap();
+            let err = assemble_file(dir.join("main.asm")).unwrap_err();
+            assert!(err.to_string().starts_with("error: cannot read `"));
+            assert!(!err.to_string().contains("-->"));
+      }
+
+      #[test]
+      fn caching_assembled_programs() {
+            let dir = ::std::env::temp_dir().join(format!("nvm-cache-{}", ::std::process::id()));
+            fs::create_dir_all(&dir).unwrap();
+            let lib = dir.join("lib.asm");
+            fs::write(&lib, "SETB #1\n").unwrap();
+            let src = format!("SETA #2\n.include \"{}\"\nHALT", lib.display());
+
+            let mut cache = AssemblerCache::new();
+            let program = cache.assemble(&src).unwrap();
+            assert_eq!(cache.hits(), 0);
+            assert_eq!(cache.assemble(&src), Ok(program.clone()));
+            assert_eq!(cache.hits(), 1);
+            assert_eq!(cache.assemble("SETA #2").unwrap(), vec![Left(2), Right(Instr::SETA)]);
+            assert_eq!(cache.hits(), 1);
+
+            fs::write(&lib, "SETB #3\n").unwrap();
+            let changed = cache.assemble(&src).unwrap();
+            assert_eq!(cache.hits(), 1);
+            assert!(changed != program);
+            assert_eq!(cache.assemble(&src), Ok(changed));
+            assert_eq!(cache.hits(), 2);
+
+            assert!(cache.assemble("SETA #x").is_err());
+            assert!(cache.assemble("SETA #x").is_err());
+            assert_eq!(cache.hits(), 2);
+            fs::remove_dir_all(&dir).unwrap();
+      }
+
+      proptest! {
+            #[test]
+            fn assemble_inverts_disassemble(program in testgen::program(64)) {
+                  let src = disassemble(&program);
+                  prop_assert_eq!(assemble(&src), Ok(program.clone()), "{}", src);
+            }
+      }
+
+      #[test]
+      fn disassembling_stray_bytes() {
+            let stray = vec![Right(Instr::HALT), Left(7)];
+            assert_eq!(disassemble(&stray), ".byte 7\nHALT\n");
+            assert_eq!(assemble(&disassemble(&stray)), Ok(stray));
+      }
+
+      #[test]
+      fn position_independent_branches() {
+            let src = ".pic\n\
+                              SETA #4\n\
+                       loop:  ADDB #3\n\
+                              SUBA #1\n\
+                              BRZ done\n\
+                              JMP loop\n\
+                       done:  HALT";
+            let program = assemble(src).unwrap();
+            assert_eq!(&program[..5], &[Right(Instr::HALT), Left(0xF8), Right(Instr::JMPREL), Left(2), Right(Instr::BRZREL)]);
+
+            // Moving the code up by running padding first leaves it working
+            for base in [0, 7, 200] {
+                  let mut relocated = program.clone();
+                  relocated.extend(vec![Right(Instr::NOP); base]);
+                  let mut vm = VM::new(relocated);
+                  vm.execute().unwrap();
+                  assert_eq!((vm.A, vm.B), (0, 12), "base {}", base);
+            }
+
+            assert_eq!(assemble("JMP end\n.pic\nJMP end\nend: HALT").unwrap(),
+                       vec![Right(Instr::HALT), Left(0), Right(Instr::JMPREL), Left(4), Right(Instr::JMP)]);
+            assert_eq!(assemble("BRZREL #-2").unwrap(), vec![Left(0xFE), Right(Instr::BRZREL)]);
+            assert_eq!(assemble(".pic\nJMP 200").unwrap_err().message, "`200` is too far for a relative branch");
+            assert_eq!(assemble(".pic on").unwrap_err().message, ".pic takes no operands");
+      }
+
+      #[test]
+      fn assembling_and_running_source() {
+            let registers = assemble_and_run("SETA #6\n\
+                                              SETB #7\n\
+                                              MUL\n\
+                                              SETX #1\n\
+                                              HALT").unwrap();
+            assert_eq!((registers.A, registers.B, registers.X), (42, 7, 1));
+
+            match assemble_and_run("SETA 1, 2") {
+                  Err(RunError::Assemble(error)) => assert_eq!(error.line, 1),
+                  other => panic!("expected an assemble error, got {:?}", other),
+            }
+            let err = assemble_and_run("POPA").unwrap_err();
+            assert_eq!(err, RunError::Vm(VmError::Context { pc: 0, instr: Instr::POPA, error: Box::new(VmError::StackUnderflow) }));
+            assert_eq!(err.to_string(), "error: StackUnderflow at pc=0 executing POPA");
+      }
+
+      #[test]
+      fn padding_to_a_fixed_size() {
+            let image = assemble_padded("SETA #2\nHALT", 8).unwrap();
+            assert_eq!(image.len(), 8);
+            assert_eq!(&image[5..], &[Right(Instr::HALT), Left(2), Right(Instr::SETA)]);
+            assert!(bytecode::encode(&image)[3..].iter().all(|byte| *byte == 0));
+
+            let mut vm = VM::new(image);
+            vm.execute().unwrap();
+            assert_eq!((vm.A, vm.pc()), (2, 3));
+
+            assert_eq!(assemble_padded("HALT", 1).unwrap(), vec![Right(Instr::HALT)]);
+            assert_eq!(assemble_padded("SETA #2\nHALT", 2).unwrap_err().to_string(), "error: program is 3 bytes, over the 2 byte image");
+
+            let src = "JMP main\n.org 0x08\nmain: SETA #2\nHALT\n.align 4\nvalue: .byte 7";
+            assert_eq!(assemble_with_symbols(src).unwrap().1["value"], 12);
+            let image = assemble_padded(src, 16).unwrap();
+            let bytes = bytecode::encode(&image);
+            assert_eq!(bytes.len(), 16);
+            assert_eq!(&bytes[2..8], &[0; 6]);
+            assert_eq!(&bytes[10..13], &[bytecode::opcode(&Instr::HALT), 0, 7]);
+            assert!(bytes[13..].iter().all(|byte| *byte == 0));
+            let mut vm = VM::new(image);
+            vm.execute().unwrap();
+            assert_eq!((vm.A, vm.pc()), (2, 11));
+
+            assert_eq!(assemble("SETA #2\n.org 1").unwrap_err().message, ".org 1 is behind the current address 2");
+            assert_eq!(assemble(".align 0").unwrap_err().message, ".align expects a number");
+            assert_eq!(assemble_padded("SETA #2\n.org 8", 4).unwrap_err().message, "program is 8 bytes, over the 4 byte image");
+      }
+
+      #[test]
+      fn loading_16_bit_immediates() {
+            let program = assemble("SETAB #0x1234\nHALT").unwrap();
+            assert_eq!(program, vec![Right(Instr::HALT), Left(0x34), Right(Instr::SETB), Left(0x12), Right(Instr::SETA)]);
+
+            let mut vm = VM::new(program);
+            vm.execute().unwrap();
+            assert_eq!((vm.A as u16) << 8 | vm.B as u16, 0x1234);
+
+            let (_, listing) = assemble_listing("SETAB #258\nHALT").unwrap();
+            assert_eq!(listing.lines().nth(1), Some("0004: 18          HALT"));
+            assert_eq!(assemble("SETAB #0x10000").unwrap_err().message, "`#0x10000` is not a 16-bit immediate");
+      }
+
+      #[test]
+      fn immediate_and_direct_addressing() {
+            assert_eq!(assemble("ADDA #10").unwrap(), vec![Left(10), Right(Instr::ADDA)]);
+            assert_eq!(assemble("ADDA 10").unwrap(), vec![Left(10), Right(Instr::ADDAM)]);
+            assert_eq!(assemble("CMPA 0x20").unwrap(), vec![Left(0x20), Right(Instr::CMPAM)]);
+            assert_eq!(assemble("data: FILL data, #2").unwrap(), vec![Left(2), Left(0), Right(Instr::FILL)]);
+
+            let err = assemble("ADDB 10").unwrap_err();
+            assert_eq!((err.column, err.message.as_str()), (6, "ADDB expects an immediate `#value`, found `10`"));
+            assert_eq!(assemble("LDA #5").unwrap_err().message, "LDA expects an address, found `#5`");
+            assert_eq!(assemble("JMP A").unwrap_err().message, "JMP expects an address, found `A`");
+            assert_eq!(assemble("MOV A, #1").unwrap_err().message, "MOV expects a register, found `#1`");
+            assert_eq!(assemble("top: CBNE X, #3, top").unwrap(), vec![Left(0), Left(3), Left(2), Right(Instr::CBNE)]);
+      }
+
+      #[test]
+      fn signed_immediates() {
+            assert_eq!(assemble("ADDA #-1").unwrap(), vec![Left(0xFF), Right(Instr::ADDA)]);
+            assert_eq!(assemble("SETA #-128\nCMPA #-0x10").unwrap(), vec![Left(0xF0), Right(Instr::CMPA), Left(0x80), Right(Instr::SETA)]);
+
+            let mut vm = VM::new(assemble("SETA #5\nADDA #-1").unwrap());
+            vm.set_arithmetic_mode(ArithmeticMode::Wrapping);
+            vm.execute().unwrap();
+            assert_eq!(vm.A, 4);
+
+            let err = assemble("ADDA #-129").unwrap_err();
+            assert_eq!((err.column, err.message.as_str()), (6, "immediate `#-129` is out of range -128..=127"));
+            assert_eq!(assemble("ADDA #-x").unwrap_err().message, "malformed operand `#-x`");
+      }
+
+      #[test]
+      fn symbol_tables() {
+            let src = "start: SETA #3\n\
+                       loop:  SUBA #1\n\
+                       \x20      BRZ done\n\
+                       \x20      JMP loop\n\
+                       done:  CALL mul\n\
+                       \x20      HALT\n\
+                       .include \"mul\"";
+            let (program, symbols) = assemble_with_symbols(src).unwrap();
+            assert_eq!(program, assemble(src).unwrap());
+
+            let names: Vec<&str> = symbols.keys().map(|name| name.as_str()).collect();
+            assert_eq!(names, vec!["done", "loop", "mul", "mul_done", "mul_inner", "mul_next", "mul_outer", "start"]);
+            assert_eq!((symbols["start"], symbols["loop"], symbols["done"], symbols["mul"]), (0, 2, 8, 11));
+      }
+
+      #[test]
+      fn warning_about_unreachable_code() {
+            let src = "SETA #1\n\
+                       HALT\n\
+                       SETB #2\n\
+                       ADDB #1\n\
+                       loop: OUTA\n\
+                       JMP loop\n\
+                       .byte 1, 2";
+            let (program, warnings) = assemble_with_warnings(src).unwrap();
+            assert_eq!(program, assemble(src).unwrap());
+            let lines: Vec<usize> = warnings.iter().map(|warning| warning.line).collect();
+            assert_eq!(lines, vec![3, 4]);
+            assert_eq!(warnings[0].to_string(), "warning: unreachable code\n--> line 3");
+
+            let (_, warnings) = assemble_with_warnings("CALL mul\nHALT\n.include \"mul\"").unwrap();
+            assert_eq!(warnings, vec![]);
+            // Only a taken skip reaches past the HALT
+            let (_, warnings) = assemble_with_warnings("CMPA #0\nSKPNZ\nHALT\nSETB #1").unwrap();
+            assert_eq!(warnings, vec![]);
+      }
+
+      #[test]
+      fn assembling_with_aliases() {
+            let aliases: Aliases = [("LOAD", "lda"), ("store", "STA"), ("stop", "HALT")]
+                  .iter().map(|&(alias, canonical)| (alias.to_string(), canonical.to_string())).collect();
+            let program = assemble_with_aliases("load 0x10\nSTORE 0x11\nstop #2", &aliases).unwrap();
+            assert_eq!(program, assemble("LDA 0x10\nSTA 0x11\nHALT #2").unwrap());
+            assert_eq!(assemble_with_aliases("LDA 0x10", &aliases).unwrap(), assemble("LDA 0x10").unwrap());
+
+            let clash: Aliases = [("ldA".to_string(), "STA".to_string())].iter().cloned().collect();
+            let err = assemble_with_aliases("", &clash).unwrap_err();
+            assert_eq!(err.to_string(), "error: alias `ldA` clashes with a built-in mnemonic");
+            let unknown: Aliases = [("LOAD".to_string(), "FETCH".to_string())].iter().cloned().collect();
+            assert_eq!(assemble_with_aliases("", &unknown).unwrap_err().message, "alias `LOAD` stands for unknown mnemonic `FETCH`");
+
+            let directive: Aliases = [(".byte".to_string(), "HALT".to_string())].iter().cloned().collect();
+            assert_eq!(assemble_with_aliases(".byte 1", &directive).unwrap_err().message, "alias `.byte` is not a valid name");
+            let cased: Aliases = [("load", "LDA"), ("LOAD", "STA")]
+                  .iter().map(|&(alias, canonical)| (alias.to_string(), canonical.to_string())).collect();
+            assert_eq!(assemble_with_aliases("load 0x10", &cased).unwrap_err().message, "aliases `load` and `LOAD` differ only in case");
+      }
+}
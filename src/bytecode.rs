@@ -0,0 +1,267 @@
+use std::sync::OnceLock;
+
+use either::*;
+use vm::Instr;
+
+// Opcode of each instruction is its index in this table. New instructions
+// must be appended so existing bytecode keeps decoding the same way.
+pub(crate) const OPCODES: &[Instr] = &[
+      Instr::PUSHi,
+      Instr::PUSHA,
+      Instr::PUSHB,
+      Instr::PUSHX,
+      Instr::PUSHY,
+      Instr::POPA,
+      Instr::POPB,
+      Instr::POPX,
+      Instr::POPY,
+      Instr::ADDA,
+      Instr::ADDB,
+      Instr::ADDX,
+      Instr::ADDY,
+      Instr::SUBA,
+      Instr::SUBB,
+      Instr::SUBX,
+      Instr::SUBY,
+      Instr::BRZ,
+      Instr::BRN,
+      Instr::BRO,
+      Instr::SETA,
+      Instr::SETB,
+      Instr::SETX,
+      Instr::SETY,
+      Instr::HALT,
+      Instr::FILL,
+      Instr::MEMCPY,
+      Instr::MOV,
+      Instr::LDA,
+      Instr::STA,
+      Instr::LDAX,
+      Instr::LDAY,
+      Instr::STAX,
+      Instr::STAY,
+      Instr::SAVECTX,
+      Instr::LOADCTX,
+      Instr::CMPSTK,
+      Instr::OUTA,
+      Instr::INA,
+      Instr::RND,
+      Instr::CMPA,
+      Instr::CMPB,
+      Instr::CMPX,
+      Instr::CMPY,
+      Instr::BRLE,
+      Instr::BRGT,
+      Instr::JMP,
+      Instr::JMPX,
+      Instr::CALL,
+      Instr::RET,
+      Instr::ADDAM,
+      Instr::SUBAM,
+      Instr::CMPAM,
+      Instr::CBEQ,
+      Instr::CBNE,
+      Instr::HALTi,
+      Instr::TSA,
+      Instr::TAS,
+      Instr::CLRMEM,
+      Instr::SWAPN,
+      Instr::ANDLO,
+      Instr::ANDHI,
+      Instr::NOP,
+      Instr::TFA,
+      Instr::TAF,
+      Instr::POPCNT,
+      Instr::PARITY,
+      Instr::MUL,
+      Instr::DIV,
+      Instr::XCHG,
+      Instr::BRZREL,
+      Instr::BRNREL,
+      Instr::BROREL,
+      Instr::BRLEREL,
+      Instr::BRGTREL,
+      Instr::JMPREL,
+      Instr::BRZW,
+      Instr::BRNW,
+      Instr::BROW,
+      Instr::BRLEW,
+      Instr::BRGTW,
+      Instr::JMPW,
+      Instr::ROTREGS,
+      Instr::STI,
+      Instr::BSET,
+      Instr::BCLR,
+      Instr::BBS,
+      Instr::BBC,
+      Instr::SKPZ,
+      Instr::SKPNZ,
+];
+
+// Static description of an instruction, for editors and documentation tools
+#[derive(Debug, PartialEq, Clone)]
+pub struct InstrInfo {
+      pub instr: Instr,
+      pub opcode: u8,
+      pub mnemonic: String, // as written in assembly source
+      pub arity: usize,
+      pub affects_flags: bool,
+}
+
+// One entry per instruction, in opcode order
+pub fn all_instructions() -> &'static [InstrInfo] {
+      static TABLE: OnceLock<Vec<InstrInfo>> = OnceLock::new();
+      TABLE.get_or_init(|| {
+            OPCODES.iter().enumerate().map(|(opcode, instr)| InstrInfo {
+                  instr: instr.clone(),
+                  opcode: opcode as u8,
+                  mnemonic: format!("{:?}", instr),
+                  arity: instr.arity(),
+                  affects_flags: instr.affects_flags(),
+            }).collect()
+      })
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum DecodeError {
+      UnknownOpcode { offset: usize, byte: u8 },
+      MissingOperand { offset: usize, instr: Instr },
+      InvalidHex { line: usize, token: String },
+      // A data byte where an instruction should start, see `vm::decoded_program`
+      ExpectedOpcode { offset: usize },
+}
+
+pub fn opcode(instr: &Instr) -> u8 {
+      OPCODES.iter().position(|i| i == instr).unwrap() as u8
+}
+
+pub fn instr_from_opcode(byte: u8) -> Option<Instr> {
+      OPCODES.get(byte as usize).cloned()
+}
+
+pub fn encode_entry(entry: &Either<u8, Instr>) -> u8 {
+      match *entry {
+            Left(x)          => x,
+            Right(ref instr) => opcode(instr),
+      }
+}
+
+// Bytes are laid out in execution order, the reverse of a VM program
+pub fn encode(program: &[Either<u8, Instr>]) -> Vec<u8> {
+      program.iter().rev().map(encode_entry).collect()
+}
+
+pub fn decode(bytes: &[u8]) -> Result<Vec<Either<u8, Instr>>, DecodeError> {
+      let mut program = Vec::with_capacity(bytes.len());
+      let mut offset = 0;
+
+      while offset < bytes.len() {
+            let byte = bytes[offset];
+            let instr = match instr_from_opcode(byte) {
+                  Some(instr) => instr,
+                  None        => return Err(DecodeError::UnknownOpcode { offset, byte }),
+            };
+
+            let arity = instr.arity();
+            if offset + arity >= bytes.len() {
+                  return Err(DecodeError::MissingOperand { offset, instr });
+            }
+
+            program.push(Right(instr));
+            for operand in &bytes[offset + 1..offset + 1 + arity] {
+                  program.push(Left(*operand));
+            }
+            offset += 1 + arity;
+      }
+
+      program.reverse();
+      Ok(program)
+}
+
+// Parses whitespace separated hex bytes, ignoring anything after a ';'
+pub fn from_hex(text: &str) -> Result<Vec<Either<u8, Instr>>, DecodeError> {
+      let mut bytes = Vec::new();
+
+      for (line_no, line) in text.lines().enumerate() {
+            let code = match line.find(';') {
+                  Some(idx) => &line[..idx],
+                  None      => line,
+            };
+
+            for token in code.split_whitespace() {
+                  match u8::from_str_radix(token, 16) {
+                        Ok(byte) => bytes.push(byte),
+                        Err(_)   => return Err(DecodeError::InvalidHex {
+                              line: line_no + 1,
+                              token: token.to_string(),
+                        }),
+                  }
+            }
+      }
+
+      decode(&bytes)
+}
+
+#[cfg(test)]
+mod tests {
+      use super::*;
+      use testgen;
+      use vm::VM;
+
+      #[test]
+      fn opcodes_round_trip() {
+            for (i, instr) in OPCODES.iter().enumerate() {
+                  assert_eq!(opcode(instr) as usize, i);
+                  assert_eq!(instr_from_opcode(i as u8), Some(instr.clone()));
+            }
+            assert_eq!(instr_from_opcode(OPCODES.len() as u8), None);
+      }
+
+      #[test]
+      fn listing_instruction_metadata() {
+            let table = all_instructions();
+            assert_eq!(table.len(), OPCODES.len());
+            assert_eq!(table.len(), 90);
+
+            let add = &table[opcode(&Instr::ADDA) as usize];
+            assert_eq!((add.mnemonic.as_str(), add.arity, add.affects_flags), ("ADDA", 1, true));
+            let memcpy = table.iter().find(|info| info.instr == Instr::MEMCPY).unwrap();
+            assert_eq!((memcpy.opcode, memcpy.arity, memcpy.affects_flags), (0x1A, 3, false));
+            assert_eq!(table[0].mnemonic, "PUSHi");
+      }
+
+      #[test]
+      fn encoding_and_decoding_programs() {
+            let program = vec![Right(Instr::HALT), Left(5), Right(Instr::ADDA), Left(10), Right(Instr::SETA)];
+            let bytes = encode(&program);
+            assert_eq!(bytes, vec![0x14, 10, 0x09, 5, 0x18]);
+            assert_eq!(decode(&bytes), Ok(program));
+
+            assert_eq!(decode(&[0xFF]), Err(DecodeError::UnknownOpcode { offset: 0, byte: 0xFF }));
+            assert_eq!(decode(&[0x18, 0x14]), Err(DecodeError::MissingOperand { offset: 1, instr: Instr::SETA }));
+      }
+
+      #[test]
+      fn loading_hex_with_comments() {
+            let text = "; add two numbers\n\
+                        14 0A    ; SETA 10\n\
+                        \t09   05 ; ADDA 5\n\
+                        \n\
+                        18       ; HALT\n";
+            let program = from_hex(text).unwrap();
+            assert_eq!(program, vec![Right(Instr::HALT), Left(5), Right(Instr::ADDA), Left(10), Right(Instr::SETA)]);
+
+            let mut vm = VM::new(program);
+            vm.execute().unwrap();
+            assert_eq!(vm.A, 15);
+
+            assert_eq!(from_hex("14 zz"), Err(DecodeError::InvalidHex { line: 1, token: "zz".to_string() }));
+      }
+
+      proptest! {
+            #[test]
+            fn decode_inverts_encode(program in testgen::program(64)) {
+                  prop_assert_eq!(decode(&encode(&program)), Ok(program.clone()));
+            }
+      }
+}
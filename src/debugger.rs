@@ -0,0 +1,116 @@
+use std::io::{self, BufRead, Write};
+
+use assembler::parse_number;
+use vm::{Hit, StepResult, VmError, VM};
+
+const CONTINUE_LIMIT: u64 = 100_000;
+
+// Line based debugger reading one command per line from `input` until `quit`
+// or the end of input:
+//
+//   step           run one instruction
+//   continue       run until the program halts or stops at a break or
+//                  watchpoint, giving up after CONTINUE_LIMIT steps
+//   break <addr>   stop before the instruction at `addr` runs
+//   regs           print the registers
+//   mem <addr>     print the byte at `addr`
+//   quit           leave the debugger
+//
+// VM errors are reported on `output` and leave the debugger running.
+pub fn debug_repl(vm: &mut VM, input: impl BufRead, mut output: impl Write) -> io::Result<()> {
+      for line in input.lines() {
+            let line = line?;
+            let words: Vec<&str> = line.split_whitespace().collect();
+            let addr = words.get(1).and_then(|word| parse_number(word));
+
+            match (words.first().cloned(), addr) {
+                  (None, _) => (),
+                  (Some("quit"), _) => break,
+                  (Some("step"), _) => {
+                        let result = vm.step();
+                        report(vm, result, &mut output)?;
+                  }
+                  (Some("continue"), _) => {
+                        let result = run(vm);
+                        report(vm, result, &mut output)?;
+                  }
+                  (Some("break"), Some(addr)) => {
+                        vm.add_breakpoint(addr);
+                        writeln!(output, "breakpoint at {}", addr)?;
+                  }
+                  (Some("regs"), _) => {
+                        let regs = vm.registers();
+                        writeln!(output, "A={} B={} X={} Y={} SP={} CC={:?} PC={}",
+                                 regs.A, regs.B, regs.X, regs.Y, regs.SP, regs.CC, vm.pc())?;
+                  }
+                  (Some("mem"), Some(addr)) => match vm.read_mem(addr) {
+                        Some(value) => writeln!(output, "[{:#04X}] = {}", addr, value)?,
+                        None        => writeln!(output, "error: {}", VmError::MemoryOutOfBounds { addr })?,
+                  },
+                  (Some("break"), None)
+                  | (Some("mem"), None) => writeln!(output, "usage: {} <addr>", words[0])?,
+                  (Some(command), _) => writeln!(output, "unknown command `{}`", command)?,
+            }
+      }
+      Ok(())
+}
+
+// Steps until something other than plain execution happens, failing with
+// VmError::InstructionLimitExceeded once CONTINUE_LIMIT steps have run
+fn run(vm: &mut VM) -> Result<StepResult, VmError> {
+      for _ in 0..CONTINUE_LIMIT {
+            match vm.step()? {
+                  StepResult::Continued
+                  | StepResult::Branched { .. } => (),
+                  result => return Ok(result),
+            }
+      }
+      Err(VmError::InstructionLimitExceeded)
+}
+
+fn report(vm: &VM, result: Result<StepResult, VmError>, output: &mut impl Write) -> io::Result<()> {
+      match result {
+            Ok(StepResult::Halted { code }) => writeln!(output, "halted with exit code {}", code),
+            Ok(StepResult::Hit(Hit::Breakpoint(pc))) => writeln!(output, "breakpoint hit at pc={}", pc),
+            Ok(StepResult::Hit(Hit::Watchpoint(addr))) => writeln!(output, "watchpoint hit at {:#04X}", addr),
+            Ok(_) => writeln!(output, "pc={}", vm.pc()),
+            Err(VmError::InstructionLimitExceeded) => writeln!(output, "still running after {} steps, stopped at pc={}", CONTINUE_LIMIT, vm.pc()),
+            Err(error) => writeln!(output, "error: {}", error),
+      }
+}
+
+#[cfg(test)]
+mod tests {
+      use super::*;
+      use program::Program;
+
+      #[test]
+      fn scripted_debugging_session() {
+            let program = Program::new().set_a(10).add_a(5).sta(0x40).set_b(7).halt().build();
+            let mut vm = VM::new(program);
+            let script = "regs\nstep\nbreak 6\ncontinue\nregs\nmem 0x40\nfly\ncontinue\nquit\nstep\n";
+
+            let mut output = Vec::new();
+            debug_repl(&mut vm, script.as_bytes(), &mut output).unwrap();
+            let output = String::from_utf8(output).unwrap();
+            let lines: Vec<&str> = output.lines().collect();
+
+            assert_eq!(lines, vec!["A=0 B=0 X=0 Y=0 SP=255 CC=DEFAULT PC=0",
+                                   "pc=2",
+                                   "breakpoint at 6",
+                                   "breakpoint hit at pc=6",
+                                   "A=15 B=0 X=0 Y=0 SP=255 CC=DEFAULT PC=6",
+                                   "[0x40] = 15",
+                                   "unknown command `fly`",
+                                   "halted with exit code 0"]);
+            assert_eq!(vm.B, 7);
+      }
+
+      #[test]
+      fn continuing_an_endless_loop() {
+            let mut vm = VM::new(Program::new().nop().jmp(0).build());
+            let mut output = Vec::new();
+            debug_repl(&mut vm, "continue\nstep\n".as_bytes(), &mut output).unwrap();
+            assert_eq!(String::from_utf8(output).unwrap(), "still running after 100000 steps, stopped at pc=0\npc=1\n");
+      }
+}
@@ -1,5 +1,21 @@
+#![allow(dead_code)]
+
 pub mod vm;
+pub mod analysis;
+pub mod bytecode;
+pub mod assembler;
+pub mod debugger;
+pub mod program;
+pub mod replay;
+pub mod stdlib;
+pub mod trace;
+pub mod word;
+#[cfg(test)]
+mod testgen;
 extern crate either;
+#[cfg(test)]
+#[macro_use]
+extern crate proptest;
 
 fn main() {
     println!("Hello, world!");
@@ -0,0 +1,181 @@
+use either::*;
+use vm::Instr;
+
+// Fluent builder emitting instructions in execution order. `build` reverses
+// them into the layout the VM pops from.
+#[derive(Debug, Default, PartialEq, Clone)]
+pub struct Program {
+      entries: Vec<Either<u8, Instr>>,
+}
+
+impl Program {
+      pub fn new() -> Program {
+            Program { entries: Vec::new() }
+      }
+
+      // Address the next emitted instruction will have, for use as a branch
+      // target. Past 255 only wide branches can reach it.
+      pub fn here(&self) -> usize {
+            self.entries.len()
+      }
+
+      // Panics if the number of operands does not match the instruction's arity
+      pub fn emit(mut self, instr: Instr, operands: &[u8]) -> Program {
+            assert_eq!(instr.arity(), operands.len(), "wrong number of operands for {:?}", instr);
+            self.entries.push(Right(instr));
+            self.entries.extend(operands.iter().map(|x| Left(*x)));
+            self
+      }
+
+      pub fn build(self) -> Vec<Either<u8, Instr>> {
+            let mut program = self.entries;
+            program.reverse();
+            program
+      }
+
+      pub fn push(self, x: u8) -> Program { self.emit(Instr::PUSHi, &[x]) }
+      pub fn push_a(self) -> Program { self.emit(Instr::PUSHA, &[]) }
+      pub fn push_b(self) -> Program { self.emit(Instr::PUSHB, &[]) }
+      pub fn push_x(self) -> Program { self.emit(Instr::PUSHX, &[]) }
+      pub fn push_y(self) -> Program { self.emit(Instr::PUSHY, &[]) }
+
+      pub fn pop_a(self) -> Program { self.emit(Instr::POPA, &[]) }
+      pub fn pop_b(self) -> Program { self.emit(Instr::POPB, &[]) }
+      pub fn pop_x(self) -> Program { self.emit(Instr::POPX, &[]) }
+      pub fn pop_y(self) -> Program { self.emit(Instr::POPY, &[]) }
+
+      pub fn add_a(self, x: u8) -> Program { self.emit(Instr::ADDA, &[x]) }
+      pub fn add_b(self, x: u8) -> Program { self.emit(Instr::ADDB, &[x]) }
+      pub fn add_x(self, x: u8) -> Program { self.emit(Instr::ADDX, &[x]) }
+      pub fn add_y(self, x: u8) -> Program { self.emit(Instr::ADDY, &[x]) }
+
+      pub fn add_a_mem(self, addr: u8) -> Program { self.emit(Instr::ADDAM, &[addr]) }
+
+      pub fn sub_a(self, x: u8) -> Program { self.emit(Instr::SUBA, &[x]) }
+      pub fn sub_b(self, x: u8) -> Program { self.emit(Instr::SUBB, &[x]) }
+      pub fn sub_x(self, x: u8) -> Program { self.emit(Instr::SUBX, &[x]) }
+      pub fn sub_y(self, x: u8) -> Program { self.emit(Instr::SUBY, &[x]) }
+
+      pub fn sub_a_mem(self, addr: u8) -> Program { self.emit(Instr::SUBAM, &[addr]) }
+
+      pub fn set_a(self, x: u8) -> Program { self.emit(Instr::SETA, &[x]) }
+      pub fn set_b(self, x: u8) -> Program { self.emit(Instr::SETB, &[x]) }
+      pub fn set_x(self, x: u8) -> Program { self.emit(Instr::SETX, &[x]) }
+      pub fn set_y(self, x: u8) -> Program { self.emit(Instr::SETY, &[x]) }
+
+      pub fn brz(self, addr: u8) -> Program { self.emit(Instr::BRZ, &[addr]) }
+      pub fn brn(self, addr: u8) -> Program { self.emit(Instr::BRN, &[addr]) }
+      pub fn bro(self, addr: u8) -> Program { self.emit(Instr::BRO, &[addr]) }
+      pub fn brle(self, addr: u8) -> Program { self.emit(Instr::BRLE, &[addr]) }
+      pub fn brgt(self, addr: u8) -> Program { self.emit(Instr::BRGT, &[addr]) }
+      pub fn jmp(self, addr: u8) -> Program { self.emit(Instr::JMP, &[addr]) }
+      pub fn jmp_x(self) -> Program { self.emit(Instr::JMPX, &[]) }
+      pub fn skpz(self) -> Program { self.emit(Instr::SKPZ, &[]) }
+      pub fn skpnz(self) -> Program { self.emit(Instr::SKPNZ, &[]) }
+
+      // Relative branches take an offset from the next instruction's address
+      pub fn brz_rel(self, offset: i8) -> Program { self.emit(Instr::BRZREL, &[offset as u8]) }
+      pub fn brn_rel(self, offset: i8) -> Program { self.emit(Instr::BRNREL, &[offset as u8]) }
+      pub fn bro_rel(self, offset: i8) -> Program { self.emit(Instr::BROREL, &[offset as u8]) }
+      pub fn brle_rel(self, offset: i8) -> Program { self.emit(Instr::BRLEREL, &[offset as u8]) }
+      pub fn brgt_rel(self, offset: i8) -> Program { self.emit(Instr::BRGTREL, &[offset as u8]) }
+      pub fn jmp_rel(self, offset: i8) -> Program { self.emit(Instr::JMPREL, &[offset as u8]) }
+
+      // Wide branches reach addresses past 255
+      pub fn brz_wide(self, addr: u16) -> Program { self.emit(Instr::BRZW, &[(addr >> 8) as u8, addr as u8]) }
+      pub fn brn_wide(self, addr: u16) -> Program { self.emit(Instr::BRNW, &[(addr >> 8) as u8, addr as u8]) }
+      pub fn bro_wide(self, addr: u16) -> Program { self.emit(Instr::BROW, &[(addr >> 8) as u8, addr as u8]) }
+      pub fn brle_wide(self, addr: u16) -> Program { self.emit(Instr::BRLEW, &[(addr >> 8) as u8, addr as u8]) }
+      pub fn brgt_wide(self, addr: u16) -> Program { self.emit(Instr::BRGTW, &[(addr >> 8) as u8, addr as u8]) }
+      pub fn jmp_wide(self, addr: u16) -> Program { self.emit(Instr::JMPW, &[(addr >> 8) as u8, addr as u8]) }
+
+      pub fn cbeq(self, reg: u8, value: u8, addr: u8) -> Program { self.emit(Instr::CBEQ, &[reg, value, addr]) }
+      pub fn cbne(self, reg: u8, value: u8, addr: u8) -> Program { self.emit(Instr::CBNE, &[reg, value, addr]) }
+      pub fn bbs(self, addr: u8, bit: u8, target: u8) -> Program { self.emit(Instr::BBS, &[addr, bit, target]) }
+      pub fn bbc(self, addr: u8, bit: u8, target: u8) -> Program { self.emit(Instr::BBC, &[addr, bit, target]) }
+      pub fn call(self, addr: u8) -> Program { self.emit(Instr::CALL, &[addr]) }
+      pub fn ret(self) -> Program { self.emit(Instr::RET, &[]) }
+
+      pub fn cmp_a(self, x: u8) -> Program { self.emit(Instr::CMPA, &[x]) }
+      pub fn cmp_b(self, x: u8) -> Program { self.emit(Instr::CMPB, &[x]) }
+      pub fn cmp_x(self, x: u8) -> Program { self.emit(Instr::CMPX, &[x]) }
+      pub fn cmp_y(self, x: u8) -> Program { self.emit(Instr::CMPY, &[x]) }
+
+      pub fn cmp_a_mem(self, addr: u8) -> Program { self.emit(Instr::CMPAM, &[addr]) }
+
+      pub fn fill(self, start: u8, len: u8) -> Program { self.emit(Instr::FILL, &[start, len]) }
+      pub fn clrmem(self, start: u8, len: u8) -> Program { self.emit(Instr::CLRMEM, &[start, len]) }
+      pub fn memcpy(self, src: u8, dest: u8, len: u8) -> Program { self.emit(Instr::MEMCPY, &[src, dest, len]) }
+
+      pub fn mov(self, dest: u8, src: u8) -> Program { self.emit(Instr::MOV, &[dest, src]) }
+      pub fn xchg(self, first: u8, second: u8) -> Program { self.emit(Instr::XCHG, &[first, second]) }
+      pub fn rotregs(self, direction: u8) -> Program { self.emit(Instr::ROTREGS, &[direction]) }
+
+      pub fn swapn(self, reg: u8) -> Program { self.emit(Instr::SWAPN, &[reg]) }
+      pub fn andlo(self, reg: u8) -> Program { self.emit(Instr::ANDLO, &[reg]) }
+      pub fn andhi(self, reg: u8) -> Program { self.emit(Instr::ANDHI, &[reg]) }
+      pub fn bset(self, reg: u8, bit: u8) -> Program { self.emit(Instr::BSET, &[reg, bit]) }
+      pub fn bclr(self, reg: u8, bit: u8) -> Program { self.emit(Instr::BCLR, &[reg, bit]) }
+
+      pub fn lda(self, addr: u8) -> Program { self.emit(Instr::LDA, &[addr]) }
+      pub fn sta(self, addr: u8) -> Program { self.emit(Instr::STA, &[addr]) }
+      pub fn sti(self, addr: u8, x: u8) -> Program { self.emit(Instr::STI, &[addr, x]) }
+      pub fn lda_x(self) -> Program { self.emit(Instr::LDAX, &[]) }
+      pub fn lda_y(self) -> Program { self.emit(Instr::LDAY, &[]) }
+      pub fn sta_x(self) -> Program { self.emit(Instr::STAX, &[]) }
+      pub fn sta_y(self) -> Program { self.emit(Instr::STAY, &[]) }
+
+      pub fn save_context(self, addr: u8) -> Program { self.emit(Instr::SAVECTX, &[addr]) }
+      pub fn load_context(self, addr: u8) -> Program { self.emit(Instr::LOADCTX, &[addr]) }
+
+      pub fn cmp_stack(self) -> Program { self.emit(Instr::CMPSTK, &[]) }
+      pub fn tsa(self) -> Program { self.emit(Instr::TSA, &[]) }
+      pub fn tas(self) -> Program { self.emit(Instr::TAS, &[]) }
+      pub fn tfa(self) -> Program { self.emit(Instr::TFA, &[]) }
+      pub fn taf(self) -> Program { self.emit(Instr::TAF, &[]) }
+      pub fn popcnt(self) -> Program { self.emit(Instr::POPCNT, &[]) }
+      pub fn parity(self) -> Program { self.emit(Instr::PARITY, &[]) }
+      pub fn mul(self) -> Program { self.emit(Instr::MUL, &[]) }
+      pub fn div(self) -> Program { self.emit(Instr::DIV, &[]) }
+
+      pub fn out_a(self) -> Program { self.emit(Instr::OUTA, &[]) }
+      pub fn in_a(self) -> Program { self.emit(Instr::INA, &[]) }
+      pub fn rnd(self) -> Program { self.emit(Instr::RND, &[]) }
+
+      pub fn nop(self) -> Program { self.emit(Instr::NOP, &[]) }
+      pub fn halt(self) -> Program { self.emit(Instr::HALT, &[]) }
+      pub fn halt_with(self, code: u8) -> Program { self.emit(Instr::HALTi, &[code]) }
+}
+
+#[cfg(test)]
+mod tests {
+      use super::*;
+      use vm::run;
+
+      #[test]
+      fn building_programs_fluently() {
+            let program = Program::new().set_a(10).add_a(5).push_a().halt().build();
+            assert_eq!(program, vec![Right(Instr::HALT), Right(Instr::PUSHA),
+                                     Left(5), Right(Instr::ADDA), Left(10), Right(Instr::SETA)]);
+            assert_eq!(run(program).unwrap().A, 15);
+
+            let copy = Program::new().fill(0, 4).memcpy(0, 8, 4).build();
+            assert_eq!(copy, vec![Left(4), Left(8), Left(0), Right(Instr::MEMCPY),
+                                  Left(4), Left(0), Right(Instr::FILL)]);
+      }
+
+      #[test]
+      fn positions_past_a_byte() {
+            let program = (0..150).fold(Program::new(), |p, _| p.set_a(1));
+            assert_eq!(program.here(), 300);
+            let program = program.jmp_wide(300).halt().build();
+            assert_eq!(program.len(), 304);
+            assert_eq!(program[0], Right(Instr::HALT));
+      }
+
+      #[test]
+      #[should_panic]
+      fn emitting_with_wrong_arity_panics() {
+            Program::new().emit(Instr::SETA, &[]);
+      }
+}
@@ -0,0 +1,75 @@
+use either::*;
+use vm::{Instr, VmBuilder, VmError, VmSnapshot, MEM_SIZE};
+
+// Everything outside the program a run depends on. Replaying it reproduces
+// the run exactly, as long as nothing poked the VM between steps. `config`
+// holds the builder settings, with its own program, memory, input and seed
+// left empty in favour of the fields here.
+#[derive(Debug, PartialEq, Clone)]
+pub struct Recording {
+      pub program: Vec<Either<u8, Instr>>,
+      pub memory: [u8; MEM_SIZE],
+      pub input: Vec<u8>,
+      pub seed: u64,
+      pub config: VmBuilder,
+}
+
+pub fn replay(rec: &Recording) -> Result<VmSnapshot, VmError> {
+      let mut vm = rec.config.clone()
+                      .program(rec.program.clone())
+                      .memory(rec.memory)
+                      .input(&rec.input)
+                      .rng_seed(rec.seed)
+                      .build();
+      vm.execute()?;
+      Ok(vm.snapshot())
+}
+
+#[cfg(test)]
+mod tests {
+      use super::*;
+      use program::Program;
+      use vm::{ArithmeticMode, StackDirection, VM};
+
+      #[test]
+      fn replaying_a_recorded_run() {
+            let program = Program::new().in_a().out_a()
+                                        .rnd().out_a()
+                                        .in_a().sta(0x20)
+                                        .rnd().out_a()
+                                        .halt()
+                                        .build();
+            let mut vm = VM::builder(program).input(&[7, 8, 9]).rng_seed(42).build();
+            vm.write_mem(0x30, 5).unwrap();
+            vm.execute().unwrap();
+
+            let rec = vm.recording();
+            assert_eq!(rec.input, vec![7, 8]);
+            assert_eq!(rec.memory[0x30], 5);
+            assert_eq!(rec.memory[0x20], 0);
+
+            let snapshot = replay(&rec).unwrap();
+            assert_eq!(snapshot, vm.snapshot());
+            assert_eq!(snapshot.mem[0x20], 8);
+
+            let reseeded = Recording { seed: 43, ..rec };
+            assert!(replay(&reseeded).unwrap().output != vm.snapshot().output);
+      }
+
+      #[test]
+      fn replaying_keeps_builder_settings() {
+            let program = Program::new().set_a(250).add_a(10).push_a().push_b().halt().build();
+            let mut vm = VM::builder(program)
+                            .arithmetic_mode(ArithmeticMode::Wrapping)
+                            .stack_direction(StackDirection::Up)
+                            .stack_pointer(0x40)
+                            .poison_registers(0xAA)
+                            .build();
+            vm.execute().unwrap();
+
+            let snapshot = replay(&vm.recording()).unwrap();
+            assert_eq!(snapshot, vm.snapshot());
+            assert_eq!((snapshot.registers.A, snapshot.registers.SP), (4, 0x42));
+            assert_eq!((snapshot.mem[0x40], snapshot.mem[0x41]), (4, 0xAA));
+      }
+}
@@ -0,0 +1,110 @@
+// Built-in assembler routines, pulled in with `.include "name"`. Each one is
+// entered with CALL and returns with RET; labels are prefixed with the routine
+// name so they do not clash with the including program.
+
+// A = X * Y, by repeated increment. Clobbers B, X and flags.
+const MUL: &str = "\
+mul:       SETA #0
+mul_outer: CMPX #0
+           BRZ mul_done
+           MOV B, Y
+mul_inner: CMPB #0
+           BRZ mul_next
+           ADDA #1
+           SUBB #1
+           JMP mul_inner
+mul_next:  SUBX #1
+           JMP mul_outer
+mul_done:  RET
+";
+
+// Writes A to the Y bytes starting at address X. Clobbers X, Y and flags.
+const MEMSET: &str = "\
+memset:      CMPY #0
+             BRZ memset_done
+             STA (X)
+             ADDX #1
+             SUBY #1
+             JMP memset
+memset_done: RET
+";
+
+// Copies B bytes from address X to address Y. Clobbers A, B, X, Y and flags.
+const MEMCPY: &str = "\
+memcpy:      CMPB #0
+             BRZ memcpy_done
+             LDA (X)
+             STA (Y)
+             ADDX #1
+             ADDY #1
+             SUBB #1
+             JMP memcpy
+memcpy_done: RET
+";
+
+// A = length of the zero terminated string at address X. Clobbers B, X and flags.
+const STRLEN: &str = "\
+strlen:      SETB #0
+strlen_loop: LDA (X)
+             CMPA #0
+             BRZ strlen_done
+             ADDB #1
+             ADDX #1
+             JMP strlen_loop
+strlen_done: MOV A, B
+             RET
+";
+
+pub fn library(name: &str) -> Option<&'static str> {
+      match name {
+            "mul"    => Some(MUL),
+            "memset" => Some(MEMSET),
+            "memcpy" => Some(MEMCPY),
+            "strlen" => Some(STRLEN),
+            _        => None,
+      }
+}
+
+#[cfg(test)]
+mod tests {
+      use assembler::assemble;
+      use vm::VM;
+
+      fn run(src: &str) -> VM {
+            let mut vm = VM::new(assemble(src).unwrap());
+            vm.execute().unwrap();
+            vm
+      }
+
+      #[test]
+      fn calling_the_multiply_routine() {
+            let vm = run("SETX #6\n\
+                          SETY #7\n\
+                          CALL mul\n\
+                          HALT\n\
+                          .include \"mul\"");
+            assert_eq!(vm.A, 42);
+            assert_eq!(vm.SP, 255);
+      }
+
+      #[test]
+      fn memory_routines() {
+            let vm = run("SETA #0x41\n\
+                          SETX #0x10\n\
+                          SETY #3\n\
+                          CALL memset\n\
+                          SETX #0x10\n\
+                          SETY #0x20\n\
+                          SETB #3\n\
+                          CALL memcpy\n\
+                          SETX #0x20\n\
+                          CALL strlen\n\
+                          HALT\n\
+                          .include \"memset\"\n\
+                          .include \"memcpy\"\n\
+                          .include \"strlen\"");
+            assert_eq!(&vm.dump()[0x10..0x14], b"AAA\0");
+            assert_eq!(&vm.dump()[0x20..0x24], b"AAA\0");
+            assert_eq!(vm.A, 3);
+      }
+}
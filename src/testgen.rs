@@ -0,0 +1,94 @@
+// Generators for randomised tests. `instr` and `program` are proptest
+// strategies for the round trip properties, which shrink a failing program
+// before reporting it. `Gen` is a fixed seed xorshift for
+// `fuzz_until_failure`, whose callers pick the seeds to run.
+
+use std::panic;
+
+use either::*;
+use proptest::collection;
+use proptest::prelude::*;
+use proptest::sample;
+use bytecode::OPCODES;
+use vm::{Instr, VM};
+
+pub fn instr() -> impl Strategy<Value = Instr> {
+      sample::select(OPCODES.to_vec())
+}
+
+// Up to `max` instructions, each followed by as many operands as its arity
+pub fn program(max: usize) -> impl Strategy<Value = Vec<Either<u8, Instr>>> {
+      let unit = instr().prop_flat_map(|instr| {
+            let arity = instr.arity();
+            (Just(instr), collection::vec(any::<u8>(), arity))
+      });
+      collection::vec(unit, 0..=max).prop_map(|units| {
+            let mut entries = Vec::new();
+            for (instr, operands) in units {
+                  entries.push(Right(instr));
+                  entries.extend(operands.into_iter().map(Left));
+            }
+            entries.reverse();
+            entries
+      })
+}
+
+pub struct Gen {
+      state: u64,
+}
+
+impl Gen {
+      pub fn new(seed: u64) -> Gen {
+            Gen { state: seed.wrapping_mul(0x9E37_79B9_7F4A_7C15) | 1 }
+      }
+
+      pub fn next(&mut self) -> u64 {
+            self.state ^= self.state << 13;
+            self.state ^= self.state >> 7;
+            self.state ^= self.state << 17;
+            self.state
+      }
+
+      pub fn byte(&mut self) -> u8 {
+            (self.next() >> 56) as u8
+      }
+
+      pub fn below(&mut self, n: usize) -> usize {
+            (self.next() % n as u64) as usize
+      }
+
+      pub fn instr(&mut self) -> Instr {
+            let idx = self.below(OPCODES.len());
+            OPCODES[idx].clone()
+      }
+
+      // Up to `max` instructions, each followed by as many operands as its arity
+      pub fn program(&mut self, max: usize) -> Vec<Either<u8, Instr>> {
+            let mut entries = Vec::new();
+            for _ in 0..self.below(max + 1) {
+                  let instr = self.instr();
+                  let arity = instr.arity();
+                  entries.push(Right(instr));
+                  for _ in 0..arity {
+                        entries.push(Left(self.byte()));
+                  }
+            }
+            entries.reverse();
+            entries
+      }
+}
+
+// Runs programs from `iterations` consecutive seeds starting at `seed`, each
+// for at most 1000 steps, and returns the first that panics the VM or leaves
+// it failing `check_invariants`
+pub fn fuzz_until_failure(iterations: usize, seed: u64) -> Option<Vec<Either<u8, Instr>>> {
+      (seed..seed + iterations as u64).map(|seed| Gen::new(seed).program(64)).find(|program| {
+            let program = program.clone();
+            let outcome = panic::catch_unwind(move || {
+                  let mut vm = VM::new(program);
+                  let _ = vm.execute_until(Some(1000), |_| false);
+                  vm.check_invariants()
+            });
+            !matches!(outcome, Ok(Ok(())))
+      })
+}
@@ -0,0 +1,105 @@
+use vm::{Flag, Instr};
+
+// One executed instruction, recorded by a VM with tracing enabled. `cycle`
+// counts from 0 and `flags` is CC after the instruction ran.
+#[derive(Debug, PartialEq, Clone)]
+pub struct TraceEvent {
+      pub cycle: u64,
+      pub pc: usize,
+      pub instr: Instr,
+      pub operands: Vec<u8>,
+      pub flags: Flag,
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum AccessKind {
+      Read,
+      Write,
+}
+
+// One memory address touched by an instruction, recorded by a VM with access
+// logging enabled. Block instructions record every address in the block.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct MemAccess {
+      pub cycle: u64,
+      pub addr: usize,
+      pub kind: AccessKind,
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum TraceFormat {
+      Csv,   // header row, then one row per event with operands space separated
+      Jsonl, // one JSON object per line
+}
+
+pub fn render(events: &[TraceEvent], fmt: TraceFormat) -> String {
+      let mut out = String::new();
+      if fmt == TraceFormat::Csv {
+            out.push_str("cycle,pc,opcode,operand,flags\n");
+      }
+
+      for event in events {
+            let operands: Vec<String> = event.operands.iter().map(|x| x.to_string()).collect();
+            let line = match fmt {
+                  TraceFormat::Csv => format!("{},{},{:?},{},{:?}\n",
+                                              event.cycle, event.pc, event.instr,
+                                              operands.join(" "), event.flags),
+                  TraceFormat::Jsonl => format!("{{\"cycle\":{},\"pc\":{},\"opcode\":\"{:?}\",\"operands\":[{}],\"flags\":\"{:?}\"}}\n",
+                                                event.cycle, event.pc, event.instr,
+                                                operands.join(","), event.flags),
+            };
+            out.push_str(&line);
+      }
+      out
+}
+
+#[cfg(test)]
+mod tests {
+      use super::*;
+      use program::Program;
+      use vm::VM;
+
+      #[test]
+      fn exporting_traces() {
+            let program = Program::new().set_a(10).add_a(5).mov(1, 0).halt().build();
+            let mut vm = VM::new(program);
+            vm.set_tracing(true);
+            vm.execute().unwrap();
+
+            let csv = vm.export_trace(TraceFormat::Csv);
+            let lines: Vec<&str> = csv.lines().collect();
+            assert_eq!(lines.len(), 1 + 4);
+            assert_eq!(lines[0], "cycle,pc,opcode,operand,flags");
+            assert_eq!(lines[1], "0,0,SETA,10,DEFAULT");
+            assert_eq!(lines[3], "2,4,MOV,1 0,DEFAULT");
+            assert_eq!(lines[4], "3,7,HALT,,DEFAULT");
+
+            let jsonl = vm.export_trace(TraceFormat::Jsonl);
+            assert_eq!(jsonl.lines().count(), 4);
+            assert_eq!(jsonl.lines().nth(2),
+                       Some("{\"cycle\":2,\"pc\":4,\"opcode\":\"MOV\",\"operands\":[1,0],\"flags\":\"DEFAULT\"}"));
+
+            let mut untraced = VM::new(Program::new().halt().build());
+            untraced.execute().unwrap();
+            assert_eq!(untraced.export_trace(TraceFormat::Csv), "cycle,pc,opcode,operand,flags\n");
+      }
+
+      #[test]
+      fn logging_memory_accesses() {
+            let program = Program::new().set_a(3).sta(0x10).push_a().lda(0x10).memcpy(0x10, 0x20, 2).pop_b().halt().build();
+            let mut vm = VM::new(program);
+            vm.set_access_logging(true);
+            vm.execute().unwrap();
+
+            let accesses: Vec<(u64, usize, AccessKind)> = vm.mem_accesses().iter().map(|a| (a.cycle, a.addr, a.kind)).collect();
+            assert_eq!(accesses, vec![(1, 0x10, AccessKind::Write),
+                                      (2, 255, AccessKind::Write),
+                                      (3, 0x10, AccessKind::Read),
+                                      (4, 0x10, AccessKind::Read), (4, 0x11, AccessKind::Read),
+                                      (4, 0x20, AccessKind::Write), (4, 0x21, AccessKind::Write),
+                                      (5, 255, AccessKind::Read)]);
+
+            vm.set_access_logging(false);
+            assert!(vm.mem_accesses().is_empty());
+      }
+}
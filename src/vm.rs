@@ -1,6 +1,24 @@
+use std::cell::RefCell;
+use std::collections::{BTreeSet, VecDeque};
+use std::error;
+use std::fmt;
+use std::mem;
+use std::ops::{ControlFlow, Range};
+use std::rc::Rc;
+
+use bytecode::DecodeError;
 use either::*;
-
-#[derive(Debug, PartialEq, Clone)]
+use replay::Recording;
+use trace::{self, AccessKind, MemAccess, TraceEvent, TraceFormat};
+use word::Word;
+
+// Flags model: only arithmetic (ADDx, SUBx, MUL, DIV) and comparison (CMPx, CMPSTK)
+// including their memory operand forms, and the nibble, bit set and bit counting operations
+// write CC, and LOADCTX and TAF restore it. Stack, register transfer, branch, memory and control instructions leave
+// CC exactly as it was, which `execute` enforces by restoring CC after any of
+// them run.
+// Register operands are encoded as 0 = A, 1 = B, 2 = X, 3 = Y.
+#[derive(Debug, PartialEq, Eq, Hash, PartialOrd, Ord, Clone)]
 pub enum Instr {
       PUSHi, // PUSH argument to stack
       PUSHA, // PUSH value in register A to stack
@@ -27,8 +45,135 @@ pub enum Instr {
       SETX,  // SET regiseter X to argument
       SETY,  // SET register Y to argument
       HALT,  // HALT execution of VM
+      FILL,  // FILL memory from start operand for length operand with value in A
+      MEMCPY,// COPY length operand bytes from source operand to destination operand
+      MOV,   // MOV value of source register operand into destination register operand
+      LDA,   // LOAD byte at address operand into A
+      STA,   // STORE A at address operand
+      LDAX,  // LOAD byte at address held in X into A, written LDA (X)
+      LDAY,  // ........................... Y ......., written LDA (Y)
+      STAX,  // STORE A at address held in X, written STA (X)
+      STAY,  // ........................... Y, written STA (Y)
+      SAVECTX, // SAVE A, B, X, Y, CC and SP to memory starting at address operand
+      LOADCTX, // LOAD ............................. from memory .................
+      CMPSTK,// POP two values and compare the second popped against the first
+      OUTA,  // WRITE A to the output port
+      INA,   // READ the next input byte into A, 0 once input is exhausted
+      RND,   // SET A to the next byte from the seeded random number generator
+      CMPA,  // COMPARE register A with argument, setting CC like CMPSTK
+      CMPB,  // ................ B
+      CMPX,  // ................ X
+      CMPY,  // ................ Y
+      BRLE,  // Branch if CC register set to FLAG::ZERO or FLAG::NEGATIVE
+      BRGT,  // Branch if CC register set to neither FLAG::ZERO nor FLAG::NEGATIVE
+      JMP,   // JUMP to address argument
+      JMPX,  // JUMP to address held in register X
+      CALL,  // PUSH return address and jump to address argument
+      RET,   // POP return address and jump to it
+      ADDAM, // ADD byte at address argument to register A, written ADDA addr
+      SUBAM, // SUB byte at address argument from register A, written SUBA addr
+      CMPAM, // COMPARE register A with byte at address argument, written CMPA addr
+      CBEQ,  // Branch to address argument if register operand equals immediate argument, CC is untouched
+      CBNE,  // ...................... if ....................... differs from ........................
+      HALTi, // HALT execution of VM with argument as exit code, written HALT #code
+      TSA,   // SET A to the stack pointer
+      TAS,   // SET the stack pointer to A
+      CLRMEM, // CLEAR memory from start operand for length operand to zero
+      SWAPN, // SWAP the high and low nibbles of register operand's low byte
+      ANDLO, // KEEP only the low nibble of register operand
+      ANDHI, // KEEP only the high nibble of register operand's low byte
+      NOP,   // DO nothing
+      TFA,   // SET A to the code of the CC flag
+      TAF,   // SET CC to the flag whose code is in A
+      POPCNT, // SET A to the number of set bits in A
+      PARITY, // SET CC to FLAG::ZERO if A has an even number of set bits
+      MUL,   // MULTIPLY register A by register B
+      DIV,   // DIVIDE register A by register B, leaving the remainder in B
+      XCHG,  // EXCHANGE the values of the two register operands
+      BRZREL, // Branch by signed offset argument from the next instruction if CC register set to FLAG::ZERO
+      BRNREL, // ................................................................................ NEGATIVE
+      BROREL, // ................................................................................ OVERFLOW
+      BRLEREL, // ............................................. if CC register set to FLAG::ZERO or FLAG::NEGATIVE
+      BRGTREL, // ............................................. if CC register set to neither FLAG::ZERO nor FLAG::NEGATIVE
+      JMPREL, // JUMP by signed offset argument from the next instruction
+      BRZW,  // Branch to 16-bit address argument if CC register set to FLAG::ZERO, for programs past 256 entries
+      BRNW,  // ............................................................. NEGATIVE
+      BROW,  // ............................................................. OVERFLOW
+      BRLEW, // .......................................... if CC register set to FLAG::ZERO or FLAG::NEGATIVE
+      BRGTW, // .......................................... if CC register set to neither FLAG::ZERO nor FLAG::NEGATIVE
+      JMPW,  // JUMP to 16-bit address argument
+      ROTREGS, // ROTATE A -> B -> X -> Y -> A if the argument is 0, the other way if it is 1
+      STI,   // STORE immediate argument at address operand
+      BSET,  // SET the bit at index argument in register operand, ZERO if the register is then 0
+      BCLR,  // CLEAR .........................................................................
+      BBS,   // Branch to address argument if the bit at index argument of the byte at address operand is set, CC is untouched
+      BBC,   // ........................................................................................ clear ..............
+      SKPZ,  // SKIP the next instruction and its operands if CC register set to FLAG::ZERO
+      SKPNZ, // ............................................ if CC register not set to FLAG::ZERO
+}
+
+impl Instr {
+      // Register an instruction family member such as ADDX operates on
+      pub fn register(&self) -> Option<Reg> {
+            match *self {
+                  Instr::PUSHA | Instr::POPA | Instr::ADDA | Instr::SUBA | Instr::SETA | Instr::CMPA
+                  | Instr::ADDAM | Instr::SUBAM | Instr::CMPAM => Some(Reg::A),
+                  Instr::PUSHB | Instr::POPB | Instr::ADDB | Instr::SUBB | Instr::SETB | Instr::CMPB => Some(Reg::B),
+                  Instr::PUSHX | Instr::POPX | Instr::ADDX | Instr::SUBX | Instr::SETX | Instr::CMPX => Some(Reg::X),
+                  Instr::PUSHY | Instr::POPY | Instr::ADDY | Instr::SUBY | Instr::SETY | Instr::CMPY => Some(Reg::Y),
+                  _ => None,
+            }
+      }
+
+      // Number of immediate operands following the opcode
+      pub fn arity(&self) -> usize {
+            match *self {
+                  Instr::PUSHi
+                  | Instr::ADDA | Instr::ADDB | Instr::ADDX | Instr::ADDY
+                  | Instr::SUBA | Instr::SUBB | Instr::SUBX | Instr::SUBY
+                  | Instr::BRZ | Instr::BRN | Instr::BRO | Instr::BRLE | Instr::BRGT | Instr::JMP
+                  | Instr::CALL
+                  | Instr::CMPA | Instr::CMPB | Instr::CMPX | Instr::CMPY
+                  | Instr::SETA | Instr::SETB | Instr::SETX | Instr::SETY
+                  | Instr::LDA | Instr::STA
+                  | Instr::SAVECTX | Instr::LOADCTX
+                  | Instr::ADDAM | Instr::SUBAM | Instr::CMPAM
+                  | Instr::HALTi
+                  | Instr::SWAPN | Instr::ANDLO | Instr::ANDHI
+                  | Instr::BRZREL | Instr::BRNREL | Instr::BROREL
+                  | Instr::BRLEREL | Instr::BRGTREL | Instr::JMPREL
+                  | Instr::ROTREGS => 1,
+                  Instr::FILL | Instr::MOV | Instr::CLRMEM | Instr::XCHG | Instr::STI
+                  | Instr::BSET | Instr::BCLR => 2,
+                  Instr::MEMCPY | Instr::CBEQ | Instr::CBNE | Instr::BBS | Instr::BBC => 3,
+                  _ if self.is_wide() => 2,
+                  _ => 0,
+            }
+      }
+
+      // Branches whose address operand takes two entries, high byte first
+      pub fn is_wide(&self) -> bool {
+            matches!(*self, Instr::BRZW | Instr::BRNW | Instr::BROW | Instr::BRLEW | Instr::BRGTW | Instr::JMPW)
+      }
+
+      pub fn affects_flags(&self) -> bool {
+            matches!(*self, Instr::ADDA | Instr::ADDB | Instr::ADDX | Instr::ADDY
+                           | Instr::SUBA | Instr::SUBB | Instr::SUBX | Instr::SUBY
+                           | Instr::LOADCTX | Instr::CMPSTK
+                           | Instr::CMPA | Instr::CMPB | Instr::CMPX | Instr::CMPY
+                           | Instr::ADDAM | Instr::SUBAM | Instr::CMPAM
+                           | Instr::SWAPN | Instr::ANDLO | Instr::ANDHI
+                           | Instr::TAF | Instr::POPCNT | Instr::PARITY
+                           | Instr::MUL | Instr::DIV | Instr::BSET | Instr::BCLR)
+      }
 }
 
+// ADD and SUB report signed and unsigned overflow separately, as on real
+// CPUs. OVERFLOW means the operands read as two's complement give a result
+// of the wrong sign (127 + 1), CARRY that the unsigned result carried out or
+// borrowed (255 + 1, 0 - 1). CC holds a single flag, so when both apply
+// (128 + 128) OVERFLOW wins. MUL sets OVERFLOW for a product that does not
+// fit and BCD arithmetic sets CARRY for a decimal carry.
 #[derive(Debug, PartialEq, Clone)]
 pub enum Flag {
       OVERFLOW,
@@ -36,429 +181,3819 @@ pub enum Flag {
       NEGATIVE,
       CARRY,
       DEFAULT,
+      DIVZERO,
 }
 
-#[derive(Clone)]
-#[allow(non_snake_case)]
-pub struct VM {
-    A: u8,
-    B: u8,
-    X: u8,
-    Y: u8, 
-    SP: usize,
-    CC: Flag,
-    PC: Option<Instr>,
-    IP: usize,
-    program: Vec<Either<u8, Instr>>,
+impl Flag {
+      pub fn code(&self) -> u8 {
+            match *self {
+                  Flag::OVERFLOW => 0,
+                  Flag::ZERO     => 1,
+                  Flag::NEGATIVE => 2,
+                  Flag::CARRY    => 3,
+                  Flag::DEFAULT  => 4,
+                  Flag::DIVZERO  => 5,
+            }
+      }
 
-    mem: [u8; 256],
+      pub fn from_code(code: u8) -> Option<Flag> {
+            match code {
+                  0 => Some(Flag::OVERFLOW),
+                  1 => Some(Flag::ZERO),
+                  2 => Some(Flag::NEGATIVE),
+                  3 => Some(Flag::CARRY),
+                  4 => Some(Flag::DEFAULT),
+                  5 => Some(Flag::DIVZERO),
+                  _ => None,
+            }
+      }
 }
 
+// What ADD/SUB store when the unsigned result carries out of or borrows past
+// the word. In every mode such a result sets CARRY, or OVERFLOW if it also
+// overflows as a signed value, so CC never claims a stored value is exact
+// when it is not.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum ArithmeticMode {
+      Legacy,     // leave the register unchanged
+      Wrapping,   // store the result modulo the word size
+      Saturating, // clamp the result to 0 or the largest word
+}
 
-impl VM {
-      fn new(program: Vec<Either<u8, Instr>>) -> VM {
-            VM {
-                  A: 0,
-                  B: 0,
-                  X: 0,
-                  Y: 0,
-                  SP: 255,
-                  CC: Flag::DEFAULT,
-                  PC: None,
-                  IP: program.len(),
-                  program,
-                  mem: [0; 256],
-            }
-    }
+#[derive(Debug, PartialEq, Eq, Hash, PartialOrd, Ord, Clone, Copy)]
+pub enum Reg {
+      A,
+      B,
+      X,
+      Y,
+}
 
-      fn execute(&mut self) {
-            loop {
-                  match self.program.pop() {
-                  Some(Right(instr)) => {
-                        self.PC = Some(instr.clone());
-                        match instr {
-                              Instr::ADDA 
-                              | Instr::ADDB 
-                              | Instr::ADDX
-                              | Instr::ADDY => self.handle_add(),
-
-                              Instr::SUBA
-                              | Instr::SUBB
-                              | Instr::SUBX
-                              | Instr::SUBY => self.handle_sub(),
-
-                              Instr::PUSHi
-                              | Instr::PUSHA
-                              | Instr::PUSHB
-                              | Instr::PUSHX
-                              | Instr::PUSHY => self.handle_push(),
-
-                              Instr::POPA
-                              | Instr::POPB
-                              | Instr::POPX
-                              | Instr::POPY  => self.handle_pop(),
-
-                              Instr::SETA
-                              | Instr::SETB
-                              | Instr::SETX 
-                              | Instr::SETY => self.handle_set_register(),
-
-                              Instr::BRN
-                              | Instr::BRZ
-                              | Instr::BRO => self.handle_branch(instr.clone()),
-
-                              Instr::HALT | _  => break,
-                        }
-                  },
-                  //Left => { self.PC = None; break; },
-                  _ => {break},
-                  }
-                  self.IP -= 1;
+impl Reg {
+      pub fn from_code(code: u8) -> Option<Reg> {
+            match code {
+                  0 => Some(Reg::A),
+                  1 => Some(Reg::B),
+                  2 => Some(Reg::X),
+                  3 => Some(Reg::Y),
+                  _ => None,
             }
       }
+}
 
-      fn handle_branch(&mut self, instr: Instr) {
-            let branch_address: usize = match self.program.pop().unwrap() {
-                  Left(x) => x as usize,
-                  _       => self.IP,
-            };
+// An instruction and its operand bytes as they appear in the program
+#[derive(Debug, PartialEq, Clone)]
+pub struct DecodedInstr {
+      pub pc: usize, // address of the opcode
+      pub instr: Instr,
+      pub operands: Vec<u8>,
+}
 
-            match instr {
-                  Instr::BRN => {
-                        if (self.CC == Flag::NEGATIVE) {
+// Decodes the instruction at `pc` without running it. Fails with
+// ExpectedInstruction if `pc` holds a byte and ExpectedImmediate if an
+// operand slot is missing or holds an instruction.
+pub fn decode_at(program: &[Either<u8, Instr>], pc: usize) -> Result<DecodedInstr, VmError> {
+      let entry = |pc: usize| program.len().checked_sub(pc + 1).map(|idx| &program[idx]);
+      let instr = match entry(pc) {
+            Some(Right(instr)) => instr.clone(),
+            Some(Left(_))      => return Err(VmError::ExpectedInstruction { pc }),
+            None               => return Err(VmError::PcOutOfBounds { pc }),
+      };
+      let operands = (pc + 1..pc + 1 + instr.arity()).map(|slot| match entry(slot) {
+            Some(Left(x)) => Ok(*x),
+            _             => Err(VmError::ExpectedImmediate { pc: slot }),
+      }).collect::<Result<_, _>>()?;
+      Ok(DecodedInstr { pc, instr, operands })
+}
 
-                        }
-                  }
+// Every instruction of the program in address order, each paired with its
+// operands, failing at the first entry that does not decode. Offsets in the
+// error are addresses. An operand slot past the end or holding an instruction
+// counts as missing.
+pub fn decoded_program(program: &[Either<u8, Instr>]) -> Result<Vec<DecodedInstr>, DecodeError> {
+      let mut decoded = Vec::new();
+      let mut pc = 0;
+      while pc < program.len() {
+            let next = decode_at(program, pc).map_err(|error| match (error, &program[program.len() - pc - 1]) {
+                  (VmError::ExpectedImmediate { .. }, Right(instr)) => DecodeError::MissingOperand { offset: pc, instr: instr.clone() },
+                  _ => DecodeError::ExpectedOpcode { offset: pc },
+            })?;
+            pc += 1 + next.operands.len();
+            decoded.push(next);
+      }
+      Ok(decoded)
+}
 
-                  Instr::BRZ => {
-                        if (self.CC == Flag::ZERO) {
+// What a single step would change, each entry holds the new value
+#[derive(Debug, PartialEq, Clone)]
+pub struct StepEffect<W = u8> {
+      pub result: StepResult,
+      pub registers: Vec<(Reg, W)>,
+      pub sp: Option<usize>,
+      pub memory: Vec<(usize, W)>,
+      pub flags: Option<Flag>,
+      pub pc: usize,
+}
 
-                        }
-                  }
+#[derive(Debug, PartialEq, Clone)]
+pub enum Hit {
+      Breakpoint(usize),
+      Watchpoint(usize),
+}
 
-                  Instr::BRO => {
-                        if (self.CC == Flag::OVERFLOW) {
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum HaltReason {
+      Instruction,  // a HALT ran
+      EndOfProgram, // there was no instruction left to fetch, including a data byte where one was expected
+}
 
-                        }
-                  }
+// What happens when the pc runs past the last entry of the program
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum PcOverflow {
+      Halt,  // halt with HaltReason::EndOfProgram
+      Error, // fail with VmError::PcOutOfBounds
+      Wrap,  // continue from address 0, the wrap takes a step of its own. An empty program still halts
+}
 
-                  _ => return
-            }
-      }
+#[derive(Debug, PartialEq, Clone)]
+pub enum StepResult {
+      Continued,
+      Branched { to: usize },
+      Halted { code: u8 },
+      Hit(Hit),
+}
 
+#[derive(Debug, PartialEq, Clone)]
+pub enum VmError {
+      MemoryOutOfBounds { addr: usize },
+      InvalidRegister(u8),
+      InvalidFlag(usize),
+      InvalidDirection(u8),
+      InvalidBit(u8),
+      JumpOutOfBounds { target: usize },
+      IllegalInstruction(Instr),
+      StackUnderflow,
+      // The tick hook returned `ControlFlow::Break` after `cycles` instructions
+      Interrupted { cycles: u64 },
+      // The program touched memory its permissions do not allow
+      ProtectionFault { addr: usize },
+      // A subroutine ran more instructions than the per-call budget allows
+      CallBudgetExceeded,
+      // A routine run by `call_with_convention` did not preserve `reg`
+      ConventionViolation { reg: Reg },
+      // The stack canary at `addr` was overwritten, see `VmBuilder::stack_canary`
+      StackCorruption { addr: usize },
+      // The pc ran past the end of the program under PcOverflow::Error
+      PcOutOfBounds { pc: usize },
+      // `execute_until` ran out of steps before its condition held
+      InstructionLimitExceeded,
+      // `patch` was asked to replace an entry it cannot
+      InvalidPatch { addr: usize },
+      // The entry at `pc` is a byte where an instruction was expected
+      ExpectedInstruction { pc: usize },
+      // The operand slot at `pc` holds an instruction or is past the end of the program
+      ExpectedImmediate { pc: usize },
+      // A loaded program has more entries than `VmBuilder::max_program_len` allows
+      ProgramTooLarge { len: usize, max: usize },
+      // Internal state that no sequence of instructions can produce
+      InvalidState(&'static str),
+      // The program halted with a nonzero exit code, see `run_expecting_success`
+      NonzeroExit(u8),
+      // An error raised while executing the instruction at `pc`
+      Context { pc: usize, instr: Instr, error: Box<VmError> },
+}
 
-      fn handle_set_register(&mut self) {
-            let arg = match self.program.pop().unwrap() {
-                  Left(x) => x,
-                  _       => 0,
-            };
+impl VmError {
+      // The underlying error, without the execution context
+      pub fn cause(&self) -> &VmError {
+            match *self {
+                  VmError::Context { ref error, .. } => error.cause(),
+                  ref error                          => error,
+            }
+      }
+}
 
-            match self.PC {
-                  Some(Instr::SETA) => {self.A = arg;},
-                  Some(Instr::SETB) => {self.B = arg;},
-                  Some(Instr::SETX) => {self.X = arg;},
-                  Some(Instr::SETY) => {self.Y = arg;},
-                  _                 => (),
+impl fmt::Display for VmError {
+      fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            match *self {
+                  VmError::MemoryOutOfBounds { addr } => write!(f, "MemoryOutOfBounds: address {:#X} is outside memory", addr),
+                  VmError::InvalidRegister(code) => write!(f, "InvalidRegister: {} is not a register code", code),
+                  VmError::InvalidFlag(code) => write!(f, "InvalidFlag: {} is not a flag code", code),
+                  VmError::InvalidDirection(code) => write!(f, "InvalidDirection: {} is not 0 or 1", code),
+                  VmError::InvalidBit(index) => write!(f, "InvalidBit: {} is past the end of the word", index),
+                  VmError::JumpOutOfBounds { target } => write!(f, "JumpOutOfBounds: {} is outside the program", target),
+                  VmError::IllegalInstruction(ref instr) => write!(f, "IllegalInstruction: {:?} is disabled", instr),
+                  VmError::StackUnderflow => write!(f, "StackUnderflow"),
+                  VmError::ProtectionFault { addr } => write!(f, "ProtectionFault: access to {:#X} is not permitted", addr),
+                  VmError::InvalidPatch { addr } => write!(f, "InvalidPatch: entry at {} cannot be replaced", addr),
+                  VmError::ExpectedInstruction { pc } => write!(f, "ExpectedInstruction: entry {} is not an instruction", pc),
+                  VmError::ExpectedImmediate { pc } => write!(f, "ExpectedImmediate: operand slot {} does not hold a byte", pc),
+                  VmError::InvalidState(reason) => write!(f, "InvalidState: {}", reason),
+                  VmError::ProgramTooLarge { len, max } => write!(f, "ProgramTooLarge: {} entries is over the limit of {}", len, max),
+                  VmError::NonzeroExit(code) => write!(f, "NonzeroExit: program halted with exit code {}", code),
+                  VmError::ConventionViolation { reg } => write!(f, "ConventionViolation: routine did not preserve {:?}", reg),
+                  VmError::StackCorruption { addr } => write!(f, "StackCorruption: canary at {:#X} was overwritten", addr),
+                  VmError::PcOutOfBounds { pc } => write!(f, "PcOutOfBounds: pc {} is past the end of the program", pc),
+                  VmError::InstructionLimitExceeded => write!(f, "InstructionLimitExceeded: condition did not hold within the step limit"),
+                  VmError::CallBudgetExceeded => write!(f, "CallBudgetExceeded: subroutine ran past its instruction budget"),
+                  VmError::Interrupted { cycles } => write!(f, "Interrupted: stopped by the tick hook after {} instructions", cycles),
+                  VmError::Context { pc, ref instr, ref error } => write!(f, "{} at pc={} executing {:?}", error, pc, instr),
             }
       }
+}
 
-      fn handle_push(&mut self) {
+impl error::Error for VmError {}
 
-            let arg = match self.PC {
-                  Some(Instr::PUSHA) => self.A,
-                  Some(Instr::PUSHB) => self.B,
-                  Some(Instr::PUSHX) => self.X,
-                  Some(Instr::PUSHY) => self.Y,
-                  Some(Instr::PUSHi) => 
-                  match self.program.pop().unwrap(){
-                        Left(x) => x,
-                        _       => 0,
-                  }
-                  _ => 0,
-            };
+// Access the program has to a region of memory. Host accesses through
+// `read_mem` and `write_mem` are not restricted.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Perms {
+      ReadWrite,
+      ReadOnly,
+      NoAccess,
+}
+
+// Which way PUSH moves SP. A downward stack starts at STACK_BASE, an upward
+// one at 0.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum StackDirection {
+      Down,
+      Up,
+}
 
-            if self.SP > 0 {
-                  self.mem[self.SP] = arg;
-                  self.SP -= 1;
+impl StackDirection {
+      fn base(&self) -> usize {
+            match *self {
+                  StackDirection::Down => STACK_BASE,
+                  StackDirection::Up   => 0,
             }
       }
+}
 
-      fn pop(&mut self) -> u8 {
-            if self.SP == 255 {
-                  0
-            } else {
-                  let arg = self.mem[self.SP + 1];
-                  self.SP += 1;
-                  arg
+// Words of memory, and the SP of an empty downward stack
+pub const MEM_SIZE: usize = 256;
+pub const STACK_BASE: usize = MEM_SIZE - 1;
+
+pub(crate) const CONTEXT_SIZE: usize = 6;
+const RUN_TO_LIMIT: u64 = 100_000;
+const CANARY: u8 = 0xA5;
+
+#[derive(Debug, PartialEq, Clone)]
+#[allow(non_snake_case)]
+pub struct Registers<W = u8> {
+      pub A: W,
+      pub B: W,
+      pub X: W,
+      pub Y: W,
+      pub SP: usize,
+      pub CC: Flag,
+}
+
+// State `VM::restore` puts back. `cycles` and `call_starts` keep call
+// budgets measuring against the frames that were active when it was taken.
+#[derive(Debug, PartialEq, Clone)]
+pub struct VmSnapshot<W = u8> {
+      pub registers: Registers<W>,
+      pub pc: usize,
+      pub mem: [W; MEM_SIZE],
+      pub output: Vec<W>,
+      pub cycles: u64,
+      pub call_starts: Vec<u64>, // cycle count when each active CALL ran
+}
+
+// Registers, flags and pc a reference implementation reports after one step,
+// see `run_conformance`
+#[derive(Debug, PartialEq, Clone)]
+pub struct ExpectedState<W = u8> {
+      pub registers: Registers<W>,
+      pub pc: usize,
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum ConformanceError<W = u8> {
+      // The state after the step run at `cycle` differs from the reference
+      Mismatch { cycle: u64, expected: ExpectedState<W>, found: ExpectedState<W> },
+      // The program halted at `cycle` with reference entries left over
+      HaltedEarly { cycle: u64 },
+      Vm(VmError),
+}
+
+impl<W: Word> fmt::Display for ConformanceError<W> {
+      fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            match *self {
+                  ConformanceError::Mismatch { cycle, ref expected, ref found } =>
+                        write!(f, "Mismatch: cycle {} expected {:?}, found {:?}", cycle, expected, found),
+                  ConformanceError::HaltedEarly { cycle } => write!(f, "HaltedEarly: program halted at cycle {}", cycle),
+                  ConformanceError::Vm(ref error) => write!(f, "{}", error),
             }
       }
+}
 
-      fn handle_pop(&mut self) {
-            match self.PC {
-                  Some(Instr::POPA) => {self.A = self.pop();},
-                  Some(Instr::POPB) => {self.B = self.pop();},
-                  Some(Instr::POPX) => {self.X = self.pop();},
-                  Some(Instr::POPY) => {self.Y = self.pop();},
-                  None | _ => (),
-            }
+impl<W> From<VmError> for ConformanceError<W> {
+      fn from(error: VmError) -> ConformanceError<W> {
+            ConformanceError::Vm(error)
       }
+}
 
-      fn handle_add(&mut self) {
-            let arg = match self.program.pop().unwrap() {
-                  Left(x) => x,
-                  _       => 0,
-            };
-            let reg_value = match self.PC {
-                  Some(Instr::ADDA) => self.A,
-                  Some(Instr::ADDB) => self.B,
-                  Some(Instr::ADDX) => self.X,
-                  Some(Instr::ADDY) => self.Y,
-                  _           => 0,
-            };
+// One difference between two snapshots, holding the old and new values
+#[derive(Debug, PartialEq, Clone)]
+pub enum StateDiff<W = u8> {
+      Register { reg: Reg, old: W, new: W },
+      Flags { old: Flag, new: Flag },
+      StackPointer { old: usize, new: usize },
+      Pc { old: usize, new: usize },
+      Memory { addr: usize, old: W, new: W },
+}
 
-            let next_reg_value = {
-                  if 255 - reg_value < arg{ 
-                        self.CC = Flag::OVERFLOW;
-                        reg_value 
-                  reg_value 
-                        reg_value 
-                  } else if (reg_value + arg) == 0 { 
-                        self.CC = Flag::ZERO; 
-                  self.CC = Flag::ZERO; 
-                        self.CC = Flag::ZERO; 
-                        0
-                  } else {
-                        self.CC = Flag::DEFAULT;
-                        arg + reg_value
+impl<W: Word> VmSnapshot<W> {
+      // How `other` differs from this snapshot, in register, flag, SP, pc and
+      // then address order. Output, cycles and call frames are not compared.
+      pub fn diff(&self, other: &VmSnapshot<W>) -> Vec<StateDiff<W>> {
+            let (old, new) = (&self.registers, &other.registers);
+            let mut diffs = Vec::new();
+
+            for &(reg, old, new) in &[(Reg::A, old.A, new.A), (Reg::B, old.B, new.B),
+                                      (Reg::X, old.X, new.X), (Reg::Y, old.Y, new.Y)] {
+                  if old != new {
+                        diffs.push(StateDiff::Register { reg, old, new });
                   }
-            };
-
-            match self.PC {
-                  Some(Instr::ADDA) => { self.A = next_reg_value; },
-                  Some(Instr::ADDB) => { self.B = next_reg_value; },
-                  Some(Instr::ADDX) => { self.X = next_reg_value; },
-                  Some(Instr::ADDY) => { self.Y = next_reg_value; },
-                  _                 => ()
             }
+            if old.CC != new.CC {
+                  diffs.push(StateDiff::Flags { old: old.CC.clone(), new: new.CC.clone() });
+            }
+            if old.SP != new.SP {
+                  diffs.push(StateDiff::StackPointer { old: old.SP, new: new.SP });
+            }
+            if self.pc != other.pc {
+                  diffs.push(StateDiff::Pc { old: self.pc, new: other.pc });
+            }
+            for (addr, (&old, &new)) in self.mem.iter().zip(other.mem.iter()).enumerate() {
+                  if old != new {
+                        diffs.push(StateDiff::Memory { addr, old, new });
+                  }
+            }
+            diffs
       }
+}
 
-      fn handle_sub(&mut self) {
-            let arg = match self.program.pop().unwrap() {
-                  Left(x) => x,
-                  _       => 0,
-            };
+// Owned final state of a VM, flags are in `registers.CC`
+#[derive(Debug, PartialEq, Clone)]
+pub struct VmParts<W = u8> {
+      pub registers: Registers<W>,
+      pub mem: [W; MEM_SIZE],
+      pub output: Vec<W>,
+}
 
-            let reg_value = match self.PC {
-                  Some(Instr::SUBA) => self.A,
-                  Some(Instr::SUBB) => self.B,
-                  Some(Instr::SUBX) => self.X,
-                  Some(Instr::SUBY) => self.Y,
-                  _ => 0,
-            };
+// Hook run by `execute` every `every` instructions
+type TickHook<W> = Rc<RefCell<dyn FnMut(&VM<W>) -> ControlFlow<()>>>;
 
-            let next_reg_value = {
-                  if reg_value < arg {
-                        self.CC = Flag::OVERFLOW;
-                        reg_value
-                  } else if reg_value - arg == 0 {
-                        self.CC = Flag::ZERO;
-                        0
-                  } else {
-                        self.CC = Flag::DEFAULT;
-                        reg_value - arg
-                  }
-            };
+// Memory mapped peripheral, see `VM::attach_device`. Addresses are absolute
+// and words wider than a byte are truncated on write.
+pub trait Device {
+      fn read(&mut self, addr: usize) -> u8;
+      fn write(&mut self, addr: usize, val: u8);
 
-            match self.PC {
-                  Some(Instr::SUBA) => {self.A = next_reg_value;},
-                  Some(Instr::SUBB) => {self.B = next_reg_value;},
-                  Some(Instr::SUBX) => {self.X = next_reg_value;},
-                  Some(Instr::SUBY) => {self.Y = next_reg_value;},
-                  _                 => ()
-            }
+      // Called once after every instruction the VM executes. Returning true
+      // raises an interrupt, see `VmBuilder::interrupt_handler`.
+      fn tick(&mut self) -> bool {
+            false
       }
 }
 
-#[cfg(test)]
-mod tests {
-      use super::*;
-    
-      #[test]
-      fn initialize_new_vm() {
-            let program = vec![Right(Instr::HALT)];
-            let program_len = program.len();
-            let vm = VM::new(program);
-            assert_eq!(vm.A, 0);
-            assert_eq!(vm.B, 0);
-            assert_eq!(vm.X, 0);
-            assert_eq!(vm.Y, 0);
-            assert_eq!(vm.SP, 255);
-            assert_eq!(vm.CC, Flag::DEFAULT);
-            assert_eq!(vm.IP, program_len);
+// Counter that reads as the number of times `every` instructions have run,
+// wrapping after 255. Writes set the count.
+pub struct Timer {
+      every: u64,
+      interrupt: bool, // raise an interrupt when the count wraps to 0
+      cycles: u64,
+      count: u8,
+}
 
-            let mut size = 0;
-            for x in vm.mem.iter() {
-                  size += 1;
-                  assert_eq!(*x, 0);
-            }
-            assert_eq!(size, 256);
+impl Timer {
+      // Panics if `every` is 0
+      pub fn new(every: u64) -> Timer {
+            assert!(every > 0, "timer period must be positive");
+            Timer { every, interrupt: false, cycles: 0, count: 0 }
       }
 
-      #[test]
-      fn adding_to_registers() {
-            let add_to_a = vec![Left(10), Right(Instr::ADDA)];
-            let add_to_b = vec![Left(10), Right(Instr::ADDB)];
-            let add_to_x = vec![Left(10), Right(Instr::ADDX)];
-            let add_to_y = vec![Left(10), Right(Instr::ADDY)];
-
-            let mut vm = VM::new(add_to_a);
-            vm.execute();
-            assert_eq!(10, vm.A);
+      pub fn interrupt_on_wrap(mut self) -> Timer {
+            self.interrupt = true;
+            self
+      }
+}
 
-            vm = VM::new(add_to_b);
-            vm.execute();
-            assert_eq!(10, vm.B);
+impl Device for Timer {
+      fn read(&mut self, _addr: usize) -> u8 {
+            self.count
+      }
 
-            vm = VM::new(add_to_x);
-            vm.execute();
-            assert_eq!(10, vm.X);
+      fn write(&mut self, _addr: usize, val: u8) {
+            self.count = val;
+      }
 
-            vm = VM::new(add_to_y);
-            vm.execute();
-            assert_eq!(10, vm.Y);
+      fn tick(&mut self) -> bool {
+            self.cycles += 1;
+            if !self.cycles.is_multiple_of(self.every) {
+                  return false;
+            }
+            self.count = self.count.wrapping_add(1);
+            self.interrupt && self.count == 0
       }
+}
 
-      #[test]
-      fn flag_setting_on_addition_to_register() {
-            let overflow = vec![Left(255), Right(Instr::ADDA), Left(1), Right(Instr::ADDA)];
-            let zero = vec![Left(0), Right(Instr::ADDA)];
-            let default = vec![Left(1), Right(Instr::ADDA)];
+type SharedDevice = Rc<RefCell<Box<dyn Device>>>;
+
+// The value ADD leaves in the register and the flag it sets. The arithmetic
+// mode only applies when the unsigned result carries out.
+fn add_words<W: Word>(lhs: W, rhs: W, mode: ArithmeticMode) -> (W, Flag) {
+      let (result, carried) = lhs.overflowing_add(rhs);
+      let value = match mode {
+            _ if !carried              => result,
+            ArithmeticMode::Legacy     => lhs,
+            ArithmeticMode::Wrapping   => result,
+            ArithmeticMode::Saturating => W::MAX,
+      };
+      // Operands of the same sign with a result of the other
+      let signed = (lhs.to_usize() ^ result.to_usize()) & (rhs.to_usize() ^ result.to_usize()) & sign_bit::<W>() != 0;
+      (value, arithmetic_flag(signed, carried, result))
+}
 
-            let mut vm = VM::new(overflow);
-            vm.execute();
-            assert_eq!(vm.CC, Flag::OVERFLOW);
+// The value SUB leaves in the register and the flag it sets. The arithmetic
+// mode only applies when the unsigned result borrows.
+fn sub_words<W: Word>(lhs: W, rhs: W, mode: ArithmeticMode) -> (W, Flag) {
+      let (result, borrowed) = lhs.overflowing_sub(rhs);
+      let value = match mode {
+            _ if !borrowed             => result,
+            ArithmeticMode::Legacy     => lhs,
+            ArithmeticMode::Wrapping   => result,
+            ArithmeticMode::Saturating => W::ZERO,
+      };
+      // Operands of different signs with a result of the subtrahend's sign
+      let signed = (lhs.to_usize() ^ rhs.to_usize()) & (lhs.to_usize() ^ result.to_usize()) & sign_bit::<W>() != 0;
+      (value, arithmetic_flag(signed, borrowed, result))
+}
 
-            vm = VM::new(zero);
-            vm.execute();
-            assert_eq!(vm.CC, Flag::ZERO);
+fn sign_bit<W: Word>() -> usize {
+      W::MAX.to_usize() / 2 + 1
+}
 
-            vm = VM::new(default);
-            vm.execute();
-            assert_eq!(vm.CC, Flag::DEFAULT);
-    }
+// Flag for an ADD or SUB result, the wrapped `result` deciding ZERO
+fn arithmetic_flag<W: Word>(signed_overflow: bool, carry: bool, result: W) -> Flag {
+      if signed_overflow {
+            Flag::OVERFLOW
+      } else if carry {
+            Flag::CARRY
+      } else if result == W::ZERO {
+            Flag::ZERO
+      } else {
+            Flag::DEFAULT
+      }
+}
 
-      #[test]
-      fn subtracting_from_registers() {
-            let sub_from_a = vec![Left(10), Right(Instr::SUBA), Left(42), Right(Instr::ADDA)];
-            let sub_from_b = vec![Left(10), Right(Instr::SUBB), Left(42), Right(Instr::ADDB)];
-            let sub_from_x = vec![Left(10), Right(Instr::SUBX), Left(42), Right(Instr::ADDX)];
-            let sub_from_y = vec![Left(10), Right(Instr::SUBY), Left(42), Right(Instr::ADDY)];
+// Registers and memory hold `W` words, bytes unless built with `with_word`
+#[derive(Clone)]
+#[allow(non_snake_case)]
+pub struct VM<W = u8> {
+    pub(crate) A: W,
+    pub(crate) B: W,
+    pub(crate) X: W,
+    pub(crate) Y: W,
+    pub(crate) SP: usize,
+    pub(crate) CC: Flag,
+    PC: Option<Instr>,
+    IP: usize,
+    program: Vec<Either<u8, Instr>>,
+    mode: ArithmeticMode,
+    decimal: bool, // ADD and SUB operate on packed BCD digits
+    stack_direction: StackDirection,
+    stack_base: usize, // SP of an empty stack
+    halted: Option<HaltReason>,
+    exit_code: u8, // operand of the HALT that stopped the VM, 0 for a plain HALT
+    breakpoints: BTreeSet<usize>,
+    watchpoints: BTreeSet<usize>,
+    resume_at: Option<usize>, // breakpoint already reported, run it on the next step
+    output: Vec<W>,
+    input: VecDeque<u8>,
+    input_log: Vec<u8>, // input bytes consumed so far, for recordings
+    seed: u64,
+    rng: u64,
+    initial_mem: Option<[W; MEM_SIZE]>, // memory as it was when the first step ran
+    disabled: Vec<Instr>,
+    cycles: u64, // instructions executed so far
+    tick: Option<(u64, TickHook<W>)>,
+    perms: [Perms; MEM_SIZE],
+    call_budget: Option<u64>,
+    call_starts: Vec<u64>, // cycle count when each active CALL ran
+    canary: Option<usize>, // address holding CANARY, checked after every instruction
+    trap: Option<usize>, // handler address for arithmetic faults
+    interrupt: Option<usize>, // handler address for device interrupts
+    pc_overflow: PcOverflow,
+    max_program_len: Option<usize>,
+    scratch_base: usize,
+    spilled: Vec<bool>, // which scratch area slots hold a spilled register
+    devices: Vec<(Range<usize>, SharedDevice)>,
+    trace: Option<Vec<TraceEvent>>,
+    accesses: Option<Vec<MemAccess>>,
+    coverage: BTreeSet<Instr>, // every distinct instruction executed successfully
+    last_branch: Option<bool>, // whether the conditional branch the last step ran was taken
+    config: VmBuilder, // settings the VM was built with, for recordings
+    #[cfg(test)]
+    fault: Option<(u64, VmError)>,
+
+    pub(crate) mem: [W; MEM_SIZE],
+}
 
-            let mut vm = VM::new(sub_from_a);
-            vm.execute();
-            assert_eq!(32, vm.A);
+#[derive(Debug, PartialEq, Clone)]
+pub struct VmBuilder {
+      program: Vec<Either<u8, Instr>>,
+      mode: ArithmeticMode,
+      decimal: bool,
+      stack_direction: StackDirection,
+      stack_base: Option<usize>,
+      mem: [u8; MEM_SIZE],
+      input: Vec<u8>,
+      seed: u64,
+      disabled: Vec<Instr>,
+      call_budget: Option<u64>,
+      canary_depth: Option<usize>,
+      trap: Option<usize>,
+      interrupt: Option<usize>,
+      pc_overflow: PcOverflow,
+      poison: u8,
+      max_program_len: Option<usize>,
+      scratch: Option<Range<usize>>,
+}
 
-            vm = VM::new(sub_from_b);
-            vm.execute();
-            assert_eq!(32, vm.B);
+impl VmBuilder {
+      pub fn new(program: Vec<Either<u8, Instr>>) -> VmBuilder {
+            VmBuilder {
+                  program,
+                  mode: ArithmeticMode::Legacy,
+                  decimal: false,
+                  stack_direction: StackDirection::Down,
+                  stack_base: None,
+                  mem: [0; MEM_SIZE],
+                  input: Vec::new(),
+                  seed: 0,
+                  disabled: Vec::new(),
+                  call_budget: None,
+                  canary_depth: None,
+                  trap: None,
+                  interrupt: None,
+                  pc_overflow: PcOverflow::Halt,
+                  poison: 0,
+                  max_program_len: None,
+                  scratch: None,
+            }
+      }
 
-            vm = VM::new(sub_from_x);
-            vm.execute();
-            assert_eq!(32, vm.X);
+      // Executing a disabled instruction traps with VmError::IllegalInstruction
+      // Replaces the program given to `new`
+      pub fn program(mut self, program: Vec<Either<u8, Instr>>) -> VmBuilder {
+            self.program = program;
+            self
+      }
 
-            vm = VM::new(sub_from_y);
-            vm.execute();
-            assert_eq!(32, vm.Y);
+      pub fn disable_instruction(mut self, instr: Instr) -> VmBuilder {
+            self.disabled.push(instr);
+            self
       }
 
-      #[test]
-      fn setting_flags_on_subtraction() {
-            let overflow = vec![Left(10), Right(Instr::SUBA)];
-            let zero     = vec![Left(10), Right(Instr::SUBA), Left(10), Right(Instr::ADDA)];
+      pub fn memory(mut self, mem: [u8; MEM_SIZE]) -> VmBuilder {
+            self.mem = mem;
+            self
+      }
 
-            let mut vm = VM::new(overflow);
-            vm.execute();
-            assert_eq!(vm.CC, Flag::OVERFLOW);
+      pub fn input(mut self, input: &[u8]) -> VmBuilder {
+            self.input = input.to_vec();
+            self
+      }
 
-            vm = VM::new(zero);
-            vm.execute();
-            assert_eq!(vm.CC, Flag::ZERO);
+      pub fn rng_seed(mut self, seed: u64) -> VmBuilder {
+            self.seed = seed;
+            self
       }
 
-      #[test]
-      fn pushing_to_stack() {
-            let push_immediate = vec![Left(10), Right(Instr::PUSHi)];
-            let push_from_a = vec![Right(Instr::PUSHA), Left(10), Right(Instr::ADDA)];
-            let push_from_b = vec![Right(Instr::PUSHB), Left(10), Right(Instr::ADDB)];
-            let push_from_x = vec![Right(Instr::PUSHX), Left(10), Right(Instr::ADDX)];
-            let push_from_y = vec![Right(Instr::PUSHY), Left(10), Right(Instr::ADDY)];
+      pub fn arithmetic_mode(mut self, mode: ArithmeticMode) -> VmBuilder {
+            self.mode = mode;
+            self
+      }
 
-            let mut vm = VM::new(push_immediate);
-            vm.execute();
-            assert_eq!(vm.mem[vm.SP+1], 10);
+      pub fn decimal_mode(mut self, on: bool) -> VmBuilder {
+            self.decimal = on;
+            self
+      }
 
-            vm = VM::new(push_from_a);
-            vm.execute();
-            assert_eq!(vm.mem[vm.SP + 1], vm.A);
+      pub fn stack_direction(mut self, direction: StackDirection) -> VmBuilder {
+            self.stack_direction = direction;
+            self
+      }
 
-            vm = VM::new(push_from_b);
-            vm.execute();
-            assert_eq!(vm.mem[vm.SP + 1], vm.B);
+      // Starts the stack at `sp` instead of the direction's default, leaving
+      // the memory on the other side of it free for data. Panics in `build` if
+      // `sp` is outside memory.
+      pub fn stack_pointer(mut self, sp: usize) -> VmBuilder {
+            self.stack_base = Some(sp);
+            self
+      }
 
-            vm = VM::new(push_from_x);
-            vm.execute();
-            assert_eq!(vm.mem[vm.SP + 1], vm.X);
+      // Each CALL may run at most `budget` instructions, including its RET,
+      // before failing with VmError::CallBudgetExceeded. Nested calls get their
+      // own budget and also count against their caller's.
+      pub fn call_budget(mut self, budget: u64) -> VmBuilder {
+            self.call_budget = Some(budget);
+            self
+      }
 
-            vm = VM::new(push_from_y);
-            vm.execute();
-            assert_eq!(vm.mem[vm.SP + 1], vm.Y);
+      // Limits the stack to `depth` entries by placing a canary byte just past
+      // them. The step running an instruction that changes the canary fails
+      // with VmError::StackCorruption. Panics if the canary would fall outside
+      // memory.
+      pub fn stack_canary(mut self, depth: usize) -> VmBuilder {
+            self.canary_depth = Some(depth);
+            self
       }
 
-      #[test]
-      fn popping_from_stack() {
-            let pop_to_a = vec![Right(Instr::POPA), Left(42), Right(Instr::PUSHi)];
-            let pop_to_b = vec![Right(Instr::POPB), Left(32), Right(Instr::PUSHi)];
-            let pop_to_x = vec![Right(Instr::POPX), Left(22), Right(Instr::PUSHi)];
-            let pop_to_y = vec![Right(Instr::POPY), Left(12), Right(Instr::PUSHi)];
+      // Treats ADD, SUB, MUL and DIV results flagged OVERFLOW or DIVZERO as
+      // faults that CALL the handler at `addr`, which can inspect CC and RET
+      // to the instruction after the fault. An unsigned CARRY is not a fault.
+      // Without a trap faults only set CC.
+      pub fn arithmetic_trap(mut self, addr: usize) -> VmBuilder {
+            self.trap = Some(addr);
+            self
+      }
 
-            let mut vm = VM::new(pop_to_a);
-            vm.execute();
-            assert_eq!(vm.A, 42);
+      // CALLs the handler at `addr` after any instruction that leaves a device
+      // raising an interrupt. Without a handler interrupts are ignored.
+      pub fn interrupt_handler(mut self, addr: usize) -> VmBuilder {
+            self.interrupt = Some(addr);
+            self
+      }
 
-            vm = VM::new(pop_to_b);
-            vm.execute();
-            assert_eq!(vm.B, 32);
+      // Starts A, B, X and Y at `val` instead of 0, so reads before writes stand out
+      pub fn poison_registers(mut self, val: u8) -> VmBuilder {
+            self.poison = val;
+            self
+      }
 
-            vm = VM::new(pop_to_x);
-            vm.execute();
-            assert_eq!(vm.X, 22);
+      // Caps the programs `load_program` accepts at `len` entries. Panics if
+      // the builder's own program is longer, so hosts taking untrusted
+      // programs should build with an empty one and load them afterwards.
+      pub fn max_program_len(mut self, len: usize) -> VmBuilder {
+            self.max_program_len = Some(len);
+            self
+      }
 
-            vm = VM::new(pop_to_y);
-            vm.execute();
-            assert_eq!(vm.Y, 12);
+      // Sets aside `slots` words of memory from `start` for `VM::spill`
+      pub fn scratch_area(mut self, start: usize, slots: usize) -> VmBuilder {
+            self.scratch = Some(start..start + slots);
+            self
       }
 
-      #[test]
-      fn setting_registers() {
-            let set_a = vec![Left(10), Right(Instr::SETA)];
-            let set_b = vec![Left(20), Right(Instr::SETB)];
-            let set_x = vec![Left(30), Right(Instr::SETX)];
+      pub fn pc_overflow(mut self, behavior: PcOverflow) -> VmBuilder {
+            self.pc_overflow = behavior;
+            self
+      }
+
+      pub fn build(self) -> VM {
+            let config = VmBuilder { program: Vec::new(), mem: [0; MEM_SIZE], input: Vec::new(), seed: 0, ..self.clone() };
+            let mut vm = VM::new(self.program);
+            vm.config = config;
+            vm.mode = self.mode;
+            vm.decimal = self.decimal;
+            vm.stack_direction = self.stack_direction;
+            let base = self.stack_base.unwrap_or(self.stack_direction.base());
+            assert!(base < vm.mem.len(), "stack pointer {} is outside memory", base);
+            vm.SP = base;
+            vm.stack_base = base;
+            vm.mem = self.mem;
+            vm.feed_input(&self.input);
+            vm.seed = self.seed;
+            vm.rng = self.seed;
+            vm.disabled = self.disabled;
+            vm.call_budget = self.call_budget;
+            vm.trap = self.trap;
+            vm.interrupt = self.interrupt;
+            vm.pc_overflow = self.pc_overflow;
+            if let Some(max) = self.max_program_len {
+                  assert!(vm.program.len() <= max, "program of {} entries is over the limit of {}", vm.program.len(), max);
+            }
+            vm.max_program_len = self.max_program_len;
+            if let Some(scratch) = self.scratch {
+                  assert!(scratch.end <= vm.mem.len(), "scratch area {:?} is outside memory", scratch);
+                  vm.spilled = vec![false; scratch.len()];
+                  vm.scratch_base = scratch.start;
+            }
+            vm.A = self.poison;
+            vm.B = self.poison;
+            vm.X = self.poison;
+            vm.Y = self.poison;
+            if let Some(depth) = self.canary_depth {
+                  let addr = match self.stack_direction {
+                        StackDirection::Down => base.checked_sub(depth),
+                        StackDirection::Up   => Some(base + depth).filter(|addr| *addr < vm.mem.len()),
+                  };
+                  let addr = addr.expect("stack canary must lie inside memory");
+                  vm.mem[addr] = CANARY;
+                  vm.canary = Some(addr);
+            }
+            vm
+      }
+}
+
+
+impl VM {
+      pub fn new(program: Vec<Either<u8, Instr>>) -> VM {
+            VM::with_word(program)
+      }
+
+      pub fn builder(program: Vec<Either<u8, Instr>>) -> VmBuilder {
+            VmBuilder::new(program)
+      }
+
+      // Everything external the run so far depended on, so it can be replayed
+      pub fn recording(&self) -> Recording {
+            Recording {
+                  program: self.program.clone(),
+                  memory: self.initial_mem.unwrap_or(self.mem),
+                  input: self.input_log.clone(),
+                  seed: self.seed,
+                  config: self.config.clone(),
+            }
+      }
+}
+
+impl<W: Word> VM<W> {
+      // A VM whose registers and memory hold `W`, e.g. `VM::<u16>::with_word`
+      pub fn with_word(program: Vec<Either<u8, Instr>>) -> VM<W> {
+            VM {
+                  A: W::ZERO,
+                  B: W::ZERO,
+                  X: W::ZERO,
+                  Y: W::ZERO,
+                  SP: STACK_BASE,
+                  stack_base: STACK_BASE,
+                  CC: Flag::DEFAULT,
+                  PC: None,
+                  IP: program.len(),
+                  program,
+                  mode: ArithmeticMode::Legacy,
+                  decimal: false,
+                  stack_direction: StackDirection::Down,
+                  halted: None,
+                  exit_code: 0,
+                  breakpoints: BTreeSet::new(),
+                  watchpoints: BTreeSet::new(),
+                  resume_at: None,
+                  output: Vec::new(),
+                  input: VecDeque::new(),
+                  input_log: Vec::new(),
+                  seed: 0,
+                  rng: 0,
+                  initial_mem: None,
+                  disabled: Vec::new(),
+                  cycles: 0,
+                  tick: None,
+                  perms: [Perms::ReadWrite; MEM_SIZE],
+                  call_budget: None,
+                  call_starts: Vec::new(),
+                  canary: None,
+                  trap: None,
+                  interrupt: None,
+                  pc_overflow: PcOverflow::Halt,
+                  max_program_len: None,
+                  scratch_base: 0,
+                  spilled: Vec::new(),
+                  devices: Vec::new(),
+                  trace: None,
+                  accesses: None,
+                  coverage: BTreeSet::new(),
+                  last_branch: None,
+                  config: VmBuilder::new(Vec::new()),
+                  #[cfg(test)]
+                  fault: None,
+                  mem: [W::ZERO; MEM_SIZE],
+            }
+      }
+
+      // Runs until the program halts or a breakpoint or watchpoint is hit
+      pub fn execute(&mut self) -> Result<(), VmError> {
+            loop {
+                  let before = self.cycles;
+                  match self.step()? {
+                        StepResult::Continued
+                        | StepResult::Branched { .. } => (),
+                        StepResult::Halted { .. }
+                        | StepResult::Hit(_)      => return Ok(()),
+                  }
+
+                  // A wrapping pc branches to 0 without running an instruction
+                  let ran = self.cycles != before;
+                  if let Some((every, hook)) = self.tick.clone().filter(|_| ran) {
+                        if self.cycles.is_multiple_of(every) && (*hook.borrow_mut())(self).is_break() {
+                              return Err(VmError::Interrupted { cycles: self.cycles });
+                        }
+                  }
+            }
+      }
+
+      // Runs like `execute` with the program counter moved to `start` first,
+      // failing with `VmError::JumpOutOfBounds` if it is past the program
+      pub fn execute_from(&mut self, start: usize) -> Result<(), VmError> {
+            self.jump(start)?;
+            self.execute()
+      }
+
+      fn check_canary(&self) -> Result<(), VmError> {
+            match self.canary {
+                  Some(addr) if self.mem[addr] != W::from_u8(CANARY) => Err(VmError::StackCorruption { addr }),
+                  _ => Ok(()),
+            }
+      }
+
+      // Makes the instruction run once `at_cycle` instructions have executed
+      // fail with `fault` instead, so tests can reach error paths directly
+      #[cfg(test)]
+      pub fn inject_fault(&mut self, at_cycle: u64, fault: VmError) {
+            self.fault = Some((at_cycle, fault));
+      }
+
+      // Panics listing every address whose word differs from its pair, or
+      // that is outside memory
+      #[cfg(test)]
+      pub fn assert_mem(&self, expected: &[(usize, u8)]) {
+            let mismatches: Vec<String> = expected.iter().filter_map(|&(addr, val)| match self.mem.get(addr) {
+                  Some(found) if *found == W::from_u8(val) => None,
+                  Some(found) => Some(format!("  [{:#04X}] expected {}, found {:?}", addr, val, found)),
+                  None        => Some(format!("  [{:#04X}] expected {}, outside memory", addr, val)),
+            }).collect();
+            if !mismatches.is_empty() {
+                  panic!("memory differs at {} of {} addresses:\n{}", mismatches.len(), expected.len(), mismatches.join("\n"));
+            }
+      }
+
+      // Steps until the next instruction to run is `op`, leaving it unexecuted,
+      // and returns at once if it already is. Stops early like `execute_until`,
+      // failing with `VmError::InstructionLimitExceeded` after RUN_TO_LIMIT steps.
+      pub fn run_to_opcode(&mut self, op: Instr) -> Result<(), VmError> {
+            if self.peek_instr() == Some(&op) {
+                  return Ok(());
+            }
+            self.execute_until(Some(RUN_TO_LIMIT), |vm| vm.peek_instr() == Some(&op))
+      }
+
+      // Steps once per `expected` entry, comparing the registers, flags and pc
+      // after each step with it and stopping at the first that differs
+      pub fn run_conformance(&mut self, expected: &[ExpectedState<W>]) -> Result<(), ConformanceError<W>> {
+            for expected in expected {
+                  let cycle = self.cycles;
+                  if self.halted.is_some() {
+                        return Err(ConformanceError::HaltedEarly { cycle });
+                  }
+                  self.step()?;
+
+                  let found = ExpectedState { registers: self.registers(), pc: self.pc() };
+                  if found != *expected {
+                        return Err(ConformanceError::Mismatch { cycle, expected: expected.clone(), found });
+                  }
+            }
+            Ok(())
+      }
+
+      // Steps until `done` holds after a step, or the program halts or hits a
+      // breakpoint or watchpoint. With a `limit`, fails with
+      // `VmError::InstructionLimitExceeded` instead of running more steps.
+      pub fn execute_until<F: FnMut(&VM<W>) -> bool>(&mut self, limit: Option<u64>, mut done: F) -> Result<(), VmError> {
+            let mut steps = 0;
+            loop {
+                  if limit == Some(steps) {
+                        return Err(VmError::InstructionLimitExceeded);
+                  }
+                  steps += 1;
+
+                  match self.step()? {
+                        StepResult::Continued
+                        | StepResult::Branched { .. } => (),
+                        StepResult::Halted { .. }
+                        | StepResult::Hit(_)       => return Ok(()),
+                  }
+                  if done(self) {
+                        return Ok(());
+                  }
+            }
+      }
+
+      // Calls `hook` after every `every` instructions `execute` runs, stopping
+      // with `VmError::Interrupted` if it breaks. Panics if `every` is 0.
+      pub fn set_tick<F: FnMut(&VM<W>) -> ControlFlow<()> + 'static>(&mut self, every: u64, hook: F) {
+            assert!(every > 0, "tick interval must be positive");
+            self.tick = Some((every, Rc::new(RefCell::new(hook))));
+      }
+
+      pub fn cycles(&self) -> u64 {
+            self.cycles
+      }
+
+      // Routes LDA, STA and memory operands in `range` to `dev` instead of
+      // memory. Stack, block and context instructions still use memory. Where
+      // ranges overlap the device attached last wins. Clones share devices.
+      pub fn attach_device(&mut self, range: Range<usize>, dev: Box<dyn Device>) {
+            self.devices.push((range, Rc::new(RefCell::new(dev))));
+      }
+
+      fn device(&self, addr: usize) -> Option<SharedDevice> {
+            self.devices.iter().rev().find(|(range, _)| range.contains(&addr)).map(|(_, dev)| dev.clone())
+      }
+
+      fn load(&self, addr: usize) -> W {
+            match self.device(addr) {
+                  Some(dev) => W::from_u8(dev.borrow_mut().read(addr)),
+                  None      => self.mem[addr],
+            }
+      }
+
+      fn store(&mut self, addr: usize, value: W) {
+            match self.device(addr) {
+                  Some(dev) => dev.borrow_mut().write(addr, value.to_usize() as u8),
+                  None      => self.mem[addr] = value,
+            }
+      }
+
+      // Why the VM stopped, None while it can still run
+      pub fn halt_reason(&self) -> Option<HaltReason> {
+            self.halted
+      }
+
+      // The exit code the program halted with, None while it can still run
+      pub fn exit_code(&self) -> Option<u8> {
+            self.halted.map(|_| self.exit_code)
+      }
+
+      // Records a TraceEvent for every instruction `step` runs successfully.
+      // Turning tracing off discards the events recorded so far.
+      pub fn set_tracing(&mut self, on: bool) {
+            if !on {
+                  self.trace = None;
+            } else if self.trace.is_none() {
+                  self.trace = Some(Vec::new());
+            }
+      }
+
+      pub fn trace(&self) -> &[TraceEvent] {
+            self.trace.as_ref().map_or(&[], |events| &events[..])
+      }
+
+      pub fn export_trace(&self, fmt: TraceFormat) -> String {
+            trace::render(self.trace(), fmt)
+      }
+
+      // Records a MemAccess for every address an instruction reads or writes,
+      // including stack slots. Turning logging off discards the log.
+      pub fn set_access_logging(&mut self, on: bool) {
+            if !on {
+                  self.accesses = None;
+            } else if self.accesses.is_none() {
+                  self.accesses = Some(Vec::new());
+            }
+      }
+
+      pub fn mem_accesses(&self) -> &[MemAccess] {
+            self.accesses.as_ref().map_or(&[], |accesses| &accesses[..])
+      }
+
+      // Whether the last step ran a conditional branch or skip that was taken,
+      // None if it ran anything else, including JMP, CALL and RET
+      pub fn last_branch_taken(&self) -> Option<bool> {
+            self.last_branch
+      }
+
+      // Instructions executed so far, counting memory operand and other forms
+      // such as ADDAM separately from ADDA, in opcode order
+      pub fn opcode_coverage(&self) -> BTreeSet<Instr> {
+            self.coverage.clone()
+      }
+
+      pub fn step(&mut self) -> Result<StepResult, VmError> {
+            self.last_branch = None;
+            if self.halted.is_some() {
+                  return Ok(StepResult::Halted { code: self.exit_code });
+            }
+
+            let pc = self.pc();
+            if self.breakpoints.contains(&pc) && self.resume_at != Some(pc) {
+                  self.resume_at = Some(pc);
+                  return Ok(StepResult::Hit(Hit::Breakpoint(pc)));
+            }
+            self.resume_at = None;
+
+            if let (Some(budget), Some(start)) = (self.call_budget, self.call_starts.last()) {
+                  if self.cycles - start >= budget {
+                        return Err(VmError::CallBudgetExceeded);
+                  }
+            }
+
+            let instr = match self.fetch() {
+                  Some(Right(instr)) => instr,
+                  None if self.pc_overflow == PcOverflow::Error => return Err(VmError::PcOutOfBounds { pc }),
+                  None if self.pc_overflow == PcOverflow::Wrap && !self.program.is_empty() => {
+                        self.IP = self.program.len();
+                        return Ok(StepResult::Branched { to: 0 });
+                  }
+                  _ => {
+                        self.halted = Some(HaltReason::EndOfProgram);
+                        return Ok(StepResult::Halted { code: 0 });
+                  }
+            };
+
+            #[cfg(test)]
+            {
+                  if self.fault.as_ref().map(|fault| fault.0) == Some(self.cycles) {
+                        let error = Box::new(self.fault.take().unwrap().1);
+                        return Err(VmError::Context { pc, instr, error });
+                  }
+            }
+
+            if self.disabled.contains(&instr) {
+                  let error = Box::new(VmError::IllegalInstruction(instr.clone()));
+                  return Err(VmError::Context { pc, instr, error });
+            }
+
+            if self.initial_mem.is_none() {
+                  self.initial_mem = Some(self.mem);
+            }
+
+            let watched: Vec<W> = self.watchpoints.iter().map(|addr| self.mem[*addr]).collect();
+            self.PC = Some(instr.clone());
+            let flags = self.CC.clone();
+            let affects_flags = instr.affects_flags();
+            // Only complete instructions can run, so decoding succeeds for any
+            // step that gets traced
+            let operands = match self.trace {
+                  Some(_) => self.decode(pc).map(|decoded| decoded.operands).unwrap_or_default(),
+                  None    => Vec::new(),
+            };
+
+            let mut result = self.dispatch(instr.clone()).map_err(|error| {
+                  VmError::Context { pc, instr: instr.clone(), error: Box::new(error) }
+            })?;
+            self.cycles += 1;
+            match self.PC {
+                  Some(Instr::CALL) => self.call_starts.push(self.cycles),
+                  Some(Instr::RET)  => { self.call_starts.pop(); }
+                  _                 => (),
+            }
+            if !affects_flags {
+                  self.CC = flags;
+            }
+            if let Some(handler) = self.trap.filter(|_| self.faulted()) {
+                  self.enter_handler(handler).map_err(|error| {
+                        VmError::Context { pc, instr: instr.clone(), error: Box::new(error) }
+                  })?;
+                  result = StepResult::Branched { to: handler };
+            }
+            // Every device ticks, even once one has raised an interrupt
+            let raised = self.devices.iter().fold(false, |raised, (_, dev)| dev.borrow_mut().tick() | raised);
+            if let Some(handler) = self.interrupt.filter(|_| raised) {
+                  self.enter_handler(handler).map_err(|error| {
+                        VmError::Context { pc, instr, error: Box::new(error) }
+                  })?;
+                  result = StepResult::Branched { to: handler };
+            }
+            self.coverage.insert(self.PC.clone().unwrap());
+            if let Some(ref mut events) = self.trace {
+                  events.push(TraceEvent {
+                        cycle: self.cycles - 1,
+                        pc,
+                        instr: self.PC.clone().unwrap(),
+                        operands,
+                        flags: self.CC.clone(),
+                  });
+            }
+            self.check_canary()?;
+
+            for (addr, old) in self.watchpoints.iter().zip(watched) {
+                  if self.mem[*addr] != old {
+                        return Ok(StepResult::Hit(Hit::Watchpoint(*addr)));
+                  }
+            }
+            Ok(result)
+      }
+
+      fn dispatch(&mut self, instr: Instr) -> Result<StepResult, VmError> {
+            let mut result = StepResult::Continued;
+            let reg = instr.register();
+            let target = reg.unwrap_or(Reg::A);
+
+            match instr {
+                  Instr::ADDA 
+                  | Instr::ADDB 
+                  | Instr::ADDX
+                  | Instr::ADDY => {
+                        let arg = self.immediate()?;
+                        self.handle_add(target, arg);
+                  }
+                  Instr::ADDAM => {
+                        let arg = self.memory_operand()?;
+                        self.handle_add(Reg::A, arg);
+                  }
+
+                  Instr::SUBA
+                  | Instr::SUBB
+                  | Instr::SUBX
+                  | Instr::SUBY => {
+                        let arg = self.immediate()?;
+                        self.handle_sub(target, arg);
+                  }
+                  Instr::SUBAM => {
+                        let arg = self.memory_operand()?;
+                        self.handle_sub(Reg::A, arg);
+                  }
+
+                  Instr::PUSHi
+                  | Instr::PUSHA
+                  | Instr::PUSHB
+                  | Instr::PUSHX
+                  | Instr::PUSHY => self.handle_push(reg)?,
+
+                  Instr::POPA
+                  | Instr::POPB
+                  | Instr::POPX
+                  | Instr::POPY  => self.handle_pop(target)?,
+
+                  Instr::SETA
+                  | Instr::SETB
+                  | Instr::SETX 
+                  | Instr::SETY => self.handle_set_register(target)?,
+
+                  Instr::BRN
+                  | Instr::BRZ
+                  | Instr::BRO
+                  | Instr::BRLE
+                  | Instr::BRGT
+                  | Instr::JMP
+                  | Instr::JMPX
+                  | Instr::CALL
+                  | Instr::RET
+                  | Instr::CBEQ
+                  | Instr::CBNE
+                  | Instr::BBS
+                  | Instr::BBC
+                  | Instr::BRZREL
+                  | Instr::BRNREL
+                  | Instr::BROREL
+                  | Instr::BRLEREL
+                  | Instr::BRGTREL
+                  | Instr::JMPREL
+                  | Instr::BRZW
+                  | Instr::BRNW
+                  | Instr::BROW
+                  | Instr::BRLEW
+                  | Instr::BRGTW
+                  | Instr::JMPW => {
+                        if let Some(to) = self.handle_branch(instr)? {
+                              result = StepResult::Branched { to };
+                        }
+                  }
+
+                  Instr::FILL => self.handle_fill(self.A)?,
+                  Instr::CLRMEM => self.handle_fill(W::ZERO)?,
+                  Instr::MEMCPY => self.handle_memcpy()?,
+                  Instr::MOV => self.handle_mov()?,
+                  Instr::XCHG => self.handle_xchg()?,
+                  Instr::ROTREGS => self.handle_rotate_registers()?,
+                  Instr::STI => self.handle_store_immediate()?,
+                  Instr::BSET
+                  | Instr::BCLR => self.handle_bit(instr)?,
+                  Instr::SKPZ
+                  | Instr::SKPNZ => {
+                        if let Some(to) = self.handle_skip(instr)? {
+                              result = StepResult::Branched { to };
+                        }
+                  }
+                  Instr::SWAPN
+                  | Instr::ANDLO
+                  | Instr::ANDHI => self.handle_nibbles(instr)?,
+
+                  Instr::LDA
+                  | Instr::LDAX
+                  | Instr::LDAY => self.handle_load()?,
+
+                  Instr::STA
+                  | Instr::STAX
+                  | Instr::STAY => self.handle_store()?,
+
+                  Instr::SAVECTX => self.handle_save_context()?,
+                  Instr::LOADCTX => self.handle_load_context()?,
+
+                  Instr::CMPSTK => self.handle_compare_stack()?,
+
+                  Instr::CMPA
+                  | Instr::CMPB
+                  | Instr::CMPX
+                  | Instr::CMPY => {
+                        let arg = self.immediate()?;
+                        self.compare(self.reg(target), arg);
+                  }
+                  Instr::CMPAM => {
+                        let arg = self.memory_operand()?;
+                        self.compare(self.A, arg);
+                  }
+                  Instr::OUTA => self.output.push(self.A),
+                  Instr::INA => self.handle_input(),
+                  Instr::RND => self.A = W::from_u8(self.next_random()),
+                  Instr::TSA => self.A = W::from_usize(self.SP),
+                  Instr::NOP => (),
+                  Instr::TFA => self.A = W::from_u8(self.CC.code()),
+                  Instr::TAF => self.CC = flag_from_word(self.A)?,
+                  Instr::POPCNT
+                  | Instr::PARITY => self.handle_bit_count(instr),
+                  Instr::MUL => self.handle_mul(),
+                  Instr::DIV => self.handle_div(),
+                  Instr::TAS => {
+                        let sp = self.A.to_usize();
+                        self.check_bounds(sp)?;
+                        self.SP = sp;
+                  }
+
+                  Instr::HALT
+                  | Instr::HALTi => {
+                        if instr == Instr::HALTi {
+                              self.exit_code = self.operand()?;
+                        }
+                        self.halted = Some(HaltReason::Instruction);
+                        result = StepResult::Halted { code: self.exit_code };
+                  }
+            }
+            Ok(result)
+      }
+
+      // Like `step`, but runs a CALL through to the instruction after it. Stops
+      // early if the subroutine halts, errors or hits a breakpoint, failing
+      // with `VmError::InstructionLimitExceeded` after RUN_TO_LIMIT steps.
+      pub fn step_over(&mut self) -> Result<StepResult, VmError> {
+            match self.peek_instr() {
+                  Some(&Instr::CALL) => (),
+                  _                  => return self.step(),
+            }
+
+            let ret = self.pc() + 1 + Instr::CALL.arity();
+            let sp = self.SP;
+            let temporary = self.breakpoints.insert(ret);
+            let result = self.run_to_return(ret, sp);
+            if temporary {
+                  self.breakpoints.remove(&ret);
+            }
+            result
+      }
+
+      // Runs the subroutine at `addr` as if CALLed from the current pc,
+      // stopping once it returns there. Stops early if the subroutine halts,
+      // errors or hits a breakpoint, and fails with
+      // `VmError::InstructionLimitExceeded` if it has not returned within
+      // RUN_TO_LIMIT steps.
+      pub fn call_subroutine(&mut self, addr: usize) -> Result<(), VmError> {
+            let sp = self.SP;
+            let ret = self.push_return_address()?;
+            self.jump(addr)?;
+
+            let temporary = self.breakpoints.insert(ret);
+            let result = self.run_to_return(ret, sp);
+            if temporary {
+                  self.breakpoints.remove(&ret);
+            }
+            result.map(|_| ())
+      }
+
+      // Calling convention for routines shared between programs: arguments in
+      // A and B, the result in A, X and Y preserved by the routine. Runs
+      // `call_subroutine` and fails with VmError::ConventionViolation naming
+      // the first of X and Y left changed. A routine that never returns fails
+      // like it does in `call_subroutine`, whatever it did to X and Y.
+      pub fn call_with_convention(&mut self, addr: usize) -> Result<(), VmError> {
+            let saved = [(Reg::X, self.X), (Reg::Y, self.Y)];
+            self.call_subroutine(addr)?;
+            match saved.iter().find(|&&(reg, value)| self.reg(reg) != value) {
+                  Some(&(reg, _)) => Err(VmError::ConventionViolation { reg }),
+                  None            => Ok(()),
+            }
+      }
+
+      // Recursive calls pass through `ret` with a deeper stack and keep running
+      fn run_to_return(&mut self, ret: usize, sp: usize) -> Result<StepResult, VmError> {
+            for _ in 0..RUN_TO_LIMIT {
+                  match self.step()? {
+                        StepResult::Hit(Hit::Breakpoint(pc)) if pc == ret && self.SP == sp => {
+                              return Ok(StepResult::Continued);
+                        }
+                        StepResult::Hit(Hit::Breakpoint(pc)) if pc == ret => (),
+                        StepResult::Continued
+                        | StepResult::Branched { .. } => (),
+                        result => return Ok(result),
+                  }
+            }
+            Err(VmError::InstructionLimitExceeded)
+      }
+
+      pub fn decode(&self, pc: usize) -> Result<DecodedInstr, VmError> {
+            decode_at(&self.program, pc)
+      }
+
+      fn peek_instr(&self) -> Option<&Instr> {
+            match self.IP.checked_sub(1).map(|ip| &self.program[ip]) {
+                  Some(Right(instr)) => Some(instr),
+                  _                  => None,
+            }
+      }
+
+      // Runs the next step against a clone and reports what it would change.
+      // The clone has no devices, so none tick and their addresses act as
+      // plain memory.
+      pub fn preview_step(&self) -> Result<StepEffect<W>, VmError> {
+            let mut next = self.clone();
+            next.devices.clear();
+            let result = next.step()?;
+
+            let registers = [(Reg::A, self.A, next.A), (Reg::B, self.B, next.B),
+                             (Reg::X, self.X, next.X), (Reg::Y, self.Y, next.Y)]
+                  .iter()
+                  .filter(|&&(_, old, new)| old != new)
+                  .map(|&(reg, _, new)| (reg, new))
+                  .collect();
+            let memory = self.mem.iter()
+                                 .zip(next.mem.iter())
+                                 .enumerate()
+                                 .filter(|&(_, (old, new))| old != new)
+                                 .map(|(addr, (_, new))| (addr, *new))
+                                 .collect();
+
+            Ok(StepEffect {
+                  result,
+                  registers,
+                  sp: if next.SP != self.SP { Some(next.SP) } else { None },
+                  memory,
+                  flags: if next.CC != self.CC { Some(next.CC.clone()) } else { None },
+                  pc: next.pc(),
+            })
+      }
+
+      // Address of the next entry in execution order
+      pub fn pc(&self) -> usize {
+            self.program.len() - self.IP
+      }
+
+      // Replaces the whole program and starts it again from address 0 on its
+      // next step. Registers, memory and the stack are left as they are.
+      pub fn load_program(&mut self, program: Vec<Either<u8, Instr>>) -> Result<(), VmError> {
+            if let Some(max) = self.max_program_len.filter(|max| program.len() > *max) {
+                  return Err(VmError::ProgramTooLarge { len: program.len(), max });
+            }
+            self.IP = program.len();
+            self.program = program;
+            self.PC = None;
+            self.halted = None;
+            self.exit_code = 0;
+            self.resume_at = None;
+            Ok(())
+      }
+
+      // Replaces the program entry at `addr`. Bytes replace bytes, and an
+      // instruction may replace one with at least as many operands, the
+      // operands it does not use becoming NOPs.
+      pub fn patch(&mut self, addr: usize, entry: Either<u8, Instr>) -> Result<(), VmError> {
+            let len = self.program.len();
+            let idx = match len.checked_sub(addr + 1) {
+                  Some(idx) => idx,
+                  None      => return Err(VmError::InvalidPatch { addr }),
+            };
+
+            match (&self.program[idx], &entry) {
+                  (Left(_), Left(_)) => (),
+                  (Right(old), Right(new)) if new.arity() <= old.arity() => {
+                        for slot in idx - old.arity()..idx - new.arity() {
+                              self.program[slot] = Right(Instr::NOP);
+                        }
+                  }
+                  _ => return Err(VmError::InvalidPatch { addr }),
+            }
+            self.program[idx] = entry;
+            Ok(())
+      }
+
+      // Execution stops before the instruction at `addr` runs
+      pub fn add_breakpoint(&mut self, addr: usize) {
+            self.breakpoints.insert(addr);
+      }
+
+      pub fn remove_breakpoint(&mut self, addr: usize) {
+            self.breakpoints.remove(&addr);
+      }
+
+      // Execution stops after any instruction that changes the word at `addr`
+      pub fn add_watchpoint(&mut self, addr: usize) -> Result<(), VmError> {
+            if addr >= self.mem.len() {
+                  return Err(VmError::MemoryOutOfBounds { addr });
+            }
+            self.watchpoints.insert(addr);
+            Ok(())
+      }
+
+      pub fn remove_watchpoint(&mut self, addr: usize) {
+            self.watchpoints.remove(&addr);
+      }
+
+      pub fn set_arithmetic_mode(&mut self, mode: ArithmeticMode) {
+            self.mode = mode;
+      }
+
+      // Like the 6502 D flag, makes ADD and SUB treat words as packed BCD
+      pub fn set_decimal_mode(&mut self, on: bool) {
+            self.decimal = on;
+      }
+
+      pub fn registers(&self) -> Registers<W> {
+            Registers {
+                  A: self.A,
+                  B: self.B,
+                  X: self.X,
+                  Y: self.Y,
+                  SP: self.SP,
+                  CC: self.CC.clone(),
+            }
+      }
+
+      pub fn dump(&self) -> &[W; MEM_SIZE] {
+            &self.mem
+      }
+
+      // Words written to the output port so far
+      pub fn output(&self) -> &[W] {
+            &self.output
+      }
+
+      // Takes the final state apart without copying the output buffer
+      pub fn into_parts(self) -> VmParts<W> {
+            VmParts {
+                  registers: self.registers(),
+                  mem: self.mem,
+                  output: self.output,
+            }
+      }
+
+      // FNV-1a over A, B, X, Y, CC, SP and memory, each value fed as a little
+      // endian u64. It does not depend on the platform or the Rust version, so
+      // golden hashes can be checked into tests.
+      pub fn state_hash(&self) -> u64 {
+            let mut hash: u64 = 0xCBF2_9CE4_8422_2325;
+            let mut values = vec![self.A.to_usize(), self.B.to_usize(), self.X.to_usize(), self.Y.to_usize(),
+                                  self.CC.code() as usize, self.SP];
+            values.extend(self.mem.iter().map(|x| x.to_usize()));
+
+            for value in values {
+                  for byte in (value as u64).to_le_bytes().iter() {
+                        hash ^= *byte as u64;
+                        hash = hash.wrapping_mul(0x0100_0000_01B3);
+                  }
+            }
+            hash
+      }
+
+      pub fn snapshot(&self) -> VmSnapshot<W> {
+            VmSnapshot {
+                  registers: self.registers(),
+                  pc: self.pc(),
+                  mem: self.mem,
+                  output: self.output.clone(),
+                  cycles: self.cycles,
+                  call_starts: self.call_starts.clone(),
+            }
+      }
+
+      // Puts the VM back in a snapshotted state, leaving it untouched if the
+      // snapshot fails `check_invariants`. The restored VM has not halted, so
+      // its exit code goes back to 0.
+      pub fn restore(&mut self, snapshot: &VmSnapshot<W>) -> Result<(), VmError> {
+            let mut vm = self.clone();
+            let registers = &snapshot.registers;
+            vm.A = registers.A;
+            vm.B = registers.B;
+            vm.X = registers.X;
+            vm.Y = registers.Y;
+            vm.SP = registers.SP;
+            vm.CC = registers.CC.clone();
+            vm.IP = vm.program.len().wrapping_sub(snapshot.pc);
+            vm.mem = snapshot.mem;
+            vm.output = snapshot.output.clone();
+            vm.cycles = snapshot.cycles;
+            vm.call_starts = snapshot.call_starts.clone();
+            vm.halted = None;
+            vm.exit_code = 0;
+            vm.resume_at = None;
+
+            vm.check_invariants()?;
+            *self = vm;
+            Ok(())
+      }
+
+      // CC needs no check, every Flag value is valid
+      pub fn check_invariants(&self) -> Result<(), VmError> {
+            if self.SP >= self.mem.len() {
+                  return Err(VmError::InvalidState("stack pointer outside memory"));
+            }
+            if self.IP > self.program.len() {
+                  return Err(VmError::InvalidState("program counter outside program"));
+            }
+            if self.call_starts.iter().any(|start| *start > self.cycles) {
+                  return Err(VmError::InvalidState("call started after the current cycle"));
+            }
+            Ok(())
+      }
+
+      // Queues bytes for INA to read
+      pub fn feed_input(&mut self, bytes: &[u8]) {
+            self.input.extend(bytes);
+      }
+
+      pub fn read_mem(&self, addr: usize) -> Option<W> {
+            self.mem.get(addr).cloned()
+      }
+
+      pub fn write_mem(&mut self, addr: usize, val: W) -> Result<(), VmError> {
+            match self.mem.get_mut(addr) {
+                  Some(word) => {
+                        *word = val;
+                        Ok(())
+                  }
+                  None => Err(VmError::MemoryOutOfBounds { addr }),
+            }
+      }
+
+      // Copies `data` into memory from `offset` on. Nothing is written if it
+      // would run past the end, the error naming the first address outside.
+      pub fn load_data(&mut self, offset: usize, data: &[u8]) -> Result<(), VmError> {
+            let end = offset.saturating_add(data.len());
+            if end > MEM_SIZE {
+                  return Err(VmError::MemoryOutOfBounds { addr: offset.max(MEM_SIZE) });
+            }
+            for (word, byte) in self.mem[offset..end].iter_mut().zip(data) {
+                  *word = W::from_u8(*byte);
+            }
+            Ok(())
+      }
+
+      // Stores `r` in a slot of the scratch area set up with
+      // `VmBuilder::scratch_area`, marking the slot occupied until `reload`.
+      // Panics if the slot is outside the area or already occupied.
+      pub fn spill(&mut self, r: Reg, slot: usize) {
+            assert!(slot < self.spilled.len(), "scratch slot {} is outside the scratch area", slot);
+            assert!(!self.spilled[slot], "scratch slot {} is already occupied", slot);
+            self.mem[self.scratch_base + slot] = self.reg(r);
+            self.spilled[slot] = true;
+      }
+
+      // Loads `r` back from a slot `spill` filled and frees the slot. Panics
+      // if the slot holds nothing.
+      pub fn reload(&mut self, r: Reg, slot: usize) {
+            assert!(self.slot_occupied(slot), "scratch slot {} holds no spilled register", slot);
+            *self.reg_mut(r) = self.mem[self.scratch_base + slot];
+            self.spilled[slot] = false;
+      }
+
+      pub fn slot_occupied(&self, slot: usize) -> bool {
+            self.spilled.get(slot).cloned().unwrap_or(false)
+      }
+
+      // Panics if `range` extends past the end of memory
+      pub fn protect(&mut self, range: Range<usize>, perms: Perms) {
+            for slot in self.perms[range].iter_mut() {
+                  *slot = perms;
+            }
+      }
+
+      // Every memory access goes through `check_read` or `check_write`, which
+      // also log it
+      fn check_read(&mut self, range: Range<usize>) -> Result<(), VmError> {
+            if let Some(addr) = range.clone().find(|addr| self.perms[*addr] == Perms::NoAccess) {
+                  return Err(VmError::ProtectionFault { addr });
+            }
+            self.log_accesses(range, AccessKind::Read);
+            Ok(())
+      }
+
+      fn check_write(&mut self, range: Range<usize>) -> Result<(), VmError> {
+            if let Some(addr) = range.clone().find(|addr| self.perms[*addr] != Perms::ReadWrite) {
+                  return Err(VmError::ProtectionFault { addr });
+            }
+            self.log_accesses(range, AccessKind::Write);
+            Ok(())
+      }
+
+      fn log_accesses(&mut self, range: Range<usize>, kind: AccessKind) {
+            let cycle = self.cycles;
+            if let Some(ref mut accesses) = self.accesses {
+                  accesses.extend(range.map(|addr| MemAccess { cycle, addr, kind }));
+            }
+      }
+
+      fn fetch(&mut self) -> Option<Either<u8, Instr>> {
+            if self.IP == 0 {
+                  None
+            } else {
+                  self.IP -= 1;
+                  Some(self.program[self.IP].clone())
+            }
+      }
+
+      // Operand slots must hold bytes, anything else is a mis-assembled program
+      fn operand(&mut self) -> Result<u8, VmError> {
+            let pc = self.pc();
+            match self.fetch() {
+                  Some(Left(x)) => Ok(x),
+                  _             => Err(VmError::ExpectedImmediate { pc }),
+            }
+      }
+
+      // Returns the target address if the branch was taken
+      fn handle_branch(&mut self, instr: Instr) -> Result<Option<usize>, VmError> {
+            let equal = match instr {
+                  Instr::CBEQ | Instr::CBNE => {
+                        let reg = self.register_operand()?;
+                        self.reg(reg) == self.immediate()?
+                  }
+                  Instr::BBS | Instr::BBC => {
+                        let value = self.memory_operand()?.to_usize();
+                        value & (1 << self.bit_index()?) != 0
+                  }
+                  _ => false,
+            };
+            let branch_address = match instr {
+                  Instr::JMPX => self.X.to_usize(),
+                  Instr::RET  => self.pop()?.to_usize(),
+                  // Targets before address 0 wrap to huge addresses `jump` rejects
+                  Instr::BRZREL | Instr::BRNREL | Instr::BROREL
+                  | Instr::BRLEREL | Instr::BRGTREL | Instr::JMPREL => {
+                        let offset = self.operand()? as i8;
+                        self.pc().wrapping_add_signed(offset as isize)
+                  }
+                  _ if instr.is_wide() => {
+                        let high = self.operand()? as usize;
+                        high << 8 | self.operand()? as usize
+                  }
+                  _           => self.operand()? as usize,
+            };
+
+            if instr == Instr::CALL {
+                  self.push_return_address()?;
+            }
+
+            let taken = match instr {
+                  Instr::JMP | Instr::JMPX | Instr::CALL | Instr::RET | Instr::JMPREL | Instr::JMPW => true,
+                  Instr::BRN | Instr::BRNREL | Instr::BRNW => self.CC == Flag::NEGATIVE,
+                  Instr::BRZ | Instr::BRZREL | Instr::BRZW => self.CC == Flag::ZERO,
+                  Instr::BRO | Instr::BROREL | Instr::BROW => self.CC == Flag::OVERFLOW,
+                  Instr::BRLE | Instr::BRLEREL | Instr::BRLEW => self.CC == Flag::ZERO || self.CC == Flag::NEGATIVE,
+                  Instr::BRGT | Instr::BRGTREL | Instr::BRGTW => self.CC != Flag::ZERO && self.CC != Flag::NEGATIVE,
+                  Instr::CBEQ | Instr::BBS => equal,
+                  Instr::CBNE | Instr::BBC => !equal,
+                  _          => false,
+            };
+            let conditional = !matches!(instr, Instr::JMP | Instr::JMPX | Instr::CALL | Instr::RET | Instr::JMPREL | Instr::JMPW);
+            if conditional {
+                  self.last_branch = Some(taken);
+            }
+
+            if !taken {
+                  return Ok(None);
+            }
+            self.jump(branch_address)?;
+            Ok(Some(branch_address))
+      }
+
+      // Returns the address skipped to if the skip was taken. A byte after the
+      // skip is passed over on its own.
+      fn handle_skip(&mut self, instr: Instr) -> Result<Option<usize>, VmError> {
+            let skip = match instr {
+                  Instr::SKPZ => self.CC == Flag::ZERO,
+                  _           => self.CC != Flag::ZERO,
+            };
+            self.last_branch = Some(skip);
+            if !skip {
+                  return Ok(None);
+            }
+            let size = self.peek_instr().map_or(1, |next| 1 + next.arity());
+            let to = (self.pc() + size).min(self.program.len());
+            self.jump(to)?;
+            Ok(Some(to))
+      }
+
+      // Pushes the current pc for RET and returns it
+      fn push_return_address(&mut self) -> Result<usize, VmError> {
+            let ret = self.pc();
+            if ret > W::MAX.to_usize() {
+                  return Err(VmError::JumpOutOfBounds { target: ret });
+            }
+            self.push(W::from_usize(ret))?;
+            Ok(ret)
+      }
+
+      // Calls a trap or interrupt handler like CALL would, so its RET pops the
+      // frame it pushes here rather than the enclosing call's
+      fn enter_handler(&mut self, handler: usize) -> Result<(), VmError> {
+            self.push_return_address()?;
+            self.jump(handler)?;
+            self.call_starts.push(self.cycles);
+            Ok(())
+      }
+
+      // Whether the instruction that just ran raised an arithmetic fault
+      fn faulted(&self) -> bool {
+            let arithmetic = matches!(self.PC, Some(Instr::ADDA) | Some(Instr::ADDB) | Some(Instr::ADDX) | Some(Instr::ADDY)
+                                             | Some(Instr::SUBA) | Some(Instr::SUBB) | Some(Instr::SUBX) | Some(Instr::SUBY)
+                                             | Some(Instr::ADDAM) | Some(Instr::SUBAM) | Some(Instr::MUL) | Some(Instr::DIV));
+            arithmetic && matches!(self.CC, Flag::OVERFLOW | Flag::DIVZERO)
+      }
+
+      // Jumping to the address just past the end of the program halts it
+      fn jump(&mut self, target: usize) -> Result<(), VmError> {
+            if target > self.program.len() {
+                  return Err(VmError::JumpOutOfBounds { target });
+            }
+            self.IP = self.program.len() - target;
+            Ok(())
+      }
+
+
+      fn handle_fill(&mut self, value: W) -> Result<(), VmError> {
+            let start = self.operand()? as usize;
+            let len = self.operand()? as usize;
+
+            if start + len > self.mem.len() {
+                  return Err(VmError::MemoryOutOfBounds { addr: start + len - 1 });
+            }
+            self.check_write(start..start + len)?;
+
+            for byte in self.mem[start..start + len].iter_mut() {
+                  *byte = value;
+            }
+            Ok(())
+      }
+
+      fn handle_memcpy(&mut self) -> Result<(), VmError> {
+            let src = self.operand()? as usize;
+            let dest = self.operand()? as usize;
+            let len = self.operand()? as usize;
+
+            for &start in &[src, dest] {
+                  if start + len > self.mem.len() {
+                        return Err(VmError::MemoryOutOfBounds { addr: start + len - 1 });
+                  }
+            }
+            self.check_read(src..src + len)?;
+            self.check_write(dest..dest + len)?;
+
+            // copy_within behaves like memmove, so overlapping ranges are safe
+            self.mem.copy_within(src..src + len, dest);
+            Ok(())
+      }
+
+      fn reg(&self, reg: Reg) -> W {
+            match reg {
+                  Reg::A => self.A,
+                  Reg::B => self.B,
+                  Reg::X => self.X,
+                  Reg::Y => self.Y,
+            }
+      }
+
+      fn reg_mut(&mut self, reg: Reg) -> &mut W {
+            match reg {
+                  Reg::A => &mut self.A,
+                  Reg::B => &mut self.B,
+                  Reg::X => &mut self.X,
+                  Reg::Y => &mut self.Y,
+            }
+      }
+
+      // Immediate operands are bytes widened to the word size
+      fn immediate(&mut self) -> Result<W, VmError> {
+            Ok(W::from_u8(self.operand()?))
+      }
+
+      fn register_operand(&mut self) -> Result<Reg, VmError> {
+            let code = self.operand()?;
+            Reg::from_code(code).ok_or(VmError::InvalidRegister(code))
+      }
+
+      fn handle_mov(&mut self) -> Result<(), VmError> {
+            let dest = self.register_operand()?;
+            let src = self.register_operand()?;
+            *self.reg_mut(dest) = self.reg(src);
+            Ok(())
+      }
+
+      fn handle_xchg(&mut self) -> Result<(), VmError> {
+            let first = self.register_operand()?;
+            let second = self.register_operand()?;
+            let value = self.reg(first);
+            *self.reg_mut(first) = self.reg(second);
+            *self.reg_mut(second) = value;
+            Ok(())
+      }
+
+      fn handle_rotate_registers(&mut self) -> Result<(), VmError> {
+            let mut regs = [self.A, self.B, self.X, self.Y];
+            match self.operand()? {
+                  0 => regs.rotate_right(1),
+                  1 => regs.rotate_left(1),
+                  other => return Err(VmError::InvalidDirection(other)),
+            }
+            let [a, b, x, y] = regs;
+            self.A = a;
+            self.B = b;
+            self.X = x;
+            self.Y = y;
+            Ok(())
+      }
+
+      fn bit_index(&mut self) -> Result<u8, VmError> {
+            match self.operand()? {
+                  index if index as usize >= mem::size_of::<W>() * 8 => Err(VmError::InvalidBit(index)),
+                  index => Ok(index),
+            }
+      }
+
+      // Sets ZERO if the result is 0 and DEFAULT otherwise
+      fn handle_bit(&mut self, instr: Instr) -> Result<(), VmError> {
+            let reg = self.register_operand()?;
+            let index = self.bit_index()?;
+            let value = self.reg(reg).to_usize();
+            let result = match instr {
+                  Instr::BSET => value | (1 << index),
+                  _           => value & !(1 << index),
+            };
+            self.CC = if result == 0 { Flag::ZERO } else { Flag::DEFAULT };
+            *self.reg_mut(reg) = W::from_usize(result);
+            Ok(())
+      }
+
+      // Sets ZERO if the result is 0 and DEFAULT otherwise
+      fn handle_nibbles(&mut self, instr: Instr) -> Result<(), VmError> {
+            let reg = self.register_operand()?;
+            let value = self.reg(reg).to_usize();
+            let result = match instr {
+                  Instr::SWAPN => (value & !0xFF) | ((value & 0x0F) << 4) | ((value & 0xF0) >> 4),
+                  Instr::ANDLO => value & 0x0F,
+                  _            => value & 0xF0,
+            };
+            self.CC = if result == 0 { Flag::ZERO } else { Flag::DEFAULT };
+            *self.reg_mut(reg) = W::from_usize(result);
+            Ok(())
+      }
+
+      // Both set ZERO or DEFAULT: POPCNT by whether A was 0, PARITY by whether
+      // the count is even. Only POPCNT writes A.
+      fn handle_bit_count(&mut self, instr: Instr) {
+            let count = self.A.to_usize().count_ones() as usize;
+            let zero = match instr {
+                  Instr::POPCNT => {
+                        self.A = W::from_usize(count);
+                        count == 0
+                  }
+                  _ => count.is_multiple_of(2),
+            };
+            self.CC = if zero { Flag::ZERO } else { Flag::DEFAULT };
+      }
+
+      fn memory_operand(&mut self) -> Result<W, VmError> {
+            let addr = self.operand()? as usize;
+            self.check_read(addr..addr + 1)?;
+            Ok(self.load(addr))
+      }
+
+      fn handle_load(&mut self) -> Result<(), VmError> {
+            let addr = match self.PC {
+                  Some(Instr::LDAX) => self.X.to_usize(),
+                  Some(Instr::LDAY) => self.Y.to_usize(),
+                  _                 => self.operand()? as usize,
+            };
+            self.check_bounds(addr)?;
+            self.check_read(addr..addr + 1)?;
+            self.A = self.load(addr);
+            Ok(())
+      }
+
+      fn handle_store(&mut self) -> Result<(), VmError> {
+            let addr = match self.PC {
+                  Some(Instr::STAX) => self.X.to_usize(),
+                  Some(Instr::STAY) => self.Y.to_usize(),
+                  _                 => self.operand()? as usize,
+            };
+            self.check_bounds(addr)?;
+            self.check_write(addr..addr + 1)?;
+            self.store(addr, self.A);
+            Ok(())
+      }
+
+      fn handle_store_immediate(&mut self) -> Result<(), VmError> {
+            let addr = self.operand()? as usize;
+            let value = self.immediate()?;
+            self.check_bounds(addr)?;
+            self.check_write(addr..addr + 1)?;
+            self.store(addr, value);
+            Ok(())
+      }
+
+      // Wide registers can point past the end of memory
+      fn check_bounds(&self, addr: usize) -> Result<(), VmError> {
+            if addr >= self.mem.len() {
+                  return Err(VmError::MemoryOutOfBounds { addr });
+            }
+            Ok(())
+      }
+
+      // Contexts are stored as the words A, B, X, Y, CC, SP
+      fn context_region(&mut self) -> Result<usize, VmError> {
+            let start = self.operand()? as usize;
+            if start + CONTEXT_SIZE > self.mem.len() {
+                  return Err(VmError::MemoryOutOfBounds { addr: start + CONTEXT_SIZE - 1 });
+            }
+            Ok(start)
+      }
+
+      fn handle_save_context(&mut self) -> Result<(), VmError> {
+            let start = self.context_region()?;
+            self.check_write(start..start + CONTEXT_SIZE)?;
+            let context = [self.A, self.B, self.X, self.Y, W::from_u8(self.CC.code()), W::from_usize(self.SP)];
+            self.mem[start..start + CONTEXT_SIZE].copy_from_slice(&context);
+            Ok(())
+      }
+
+      fn handle_load_context(&mut self) -> Result<(), VmError> {
+            let start = self.context_region()?;
+            self.check_read(start..start + CONTEXT_SIZE)?;
+            let context = &self.mem[start..start + CONTEXT_SIZE];
+            let flag = flag_from_word(context[4])?;
+            let sp = context[5].to_usize();
+            self.check_bounds(sp)?;
+
+            self.A = context[0];
+            self.B = context[1];
+            self.X = context[2];
+            self.Y = context[3];
+            self.CC = flag;
+            self.SP = sp;
+            Ok(())
+      }
+
+      fn handle_set_register(&mut self, reg: Reg) -> Result<(), VmError> {
+            *self.reg_mut(reg) = self.immediate()?;
+            Ok(())
+      }
+
+      fn handle_push(&mut self, reg: Option<Reg>) -> Result<(), VmError> {
+            let arg = match reg {
+                  Some(reg) => self.reg(reg),
+                  None      => self.immediate()?,
+            };
+            self.push(arg)
+      }
+
+      fn push(&mut self, value: W) -> Result<(), VmError> {
+            self.check_write(self.SP..self.SP + 1)?;
+            match self.stack_direction {
+                  StackDirection::Down if self.SP > 0 => {
+                        self.mem[self.SP] = value;
+                        self.SP -= 1;
+                  }
+                  StackDirection::Up if self.SP < MEM_SIZE - 1 => {
+                        self.mem[self.SP] = value;
+                        self.SP += 1;
+                  }
+                  _ => (),
+            }
+            Ok(())
+      }
+
+      fn pop(&mut self) -> Result<W, VmError> {
+            if self.SP == self.stack_base {
+                  return Err(VmError::StackUnderflow);
+            }
+
+            match self.stack_direction {
+                  StackDirection::Down => self.SP += 1,
+                  StackDirection::Up   => self.SP -= 1,
+            }
+            self.check_read(self.SP..self.SP + 1)?;
+            Ok(self.mem[self.SP])
+      }
+
+      fn handle_pop(&mut self, reg: Reg) -> Result<(), VmError> {
+            *self.reg_mut(reg) = self.pop()?;
+            Ok(())
+      }
+
+      fn handle_input(&mut self) {
+            self.A = match self.input.pop_front() {
+                  Some(byte) => {
+                        self.input_log.push(byte);
+                        W::from_u8(byte)
+                  }
+                  None => W::ZERO,
+            };
+      }
+
+      // xorshift64*, the state is offset so that a zero seed still produces a stream
+      fn next_random(&mut self) -> u8 {
+            let mut x = self.rng ^ 0x9E37_79B9_7F4A_7C15;
+            x ^= x >> 12;
+            x ^= x << 25;
+            x ^= x >> 27;
+            self.rng = x ^ 0x9E37_79B9_7F4A_7C15;
+            (x.wrapping_mul(0x2545_F491_4F6C_DD1D) >> 56) as u8
+      }
+
+      // ZERO if the values are equal, NEGATIVE if lhs is less than rhs
+      fn compare(&mut self, lhs: W, rhs: W) {
+            self.CC = if lhs == rhs {
+                  Flag::ZERO
+            } else if lhs < rhs {
+                  Flag::NEGATIVE
+            } else {
+                  Flag::DEFAULT
+            };
+      }
+
+      fn handle_compare_stack(&mut self) -> Result<(), VmError> {
+            let first = self.pop()?;
+            let second = self.pop()?;
+            self.compare(second, first);
+            Ok(())
+      }
+
+      fn handle_add(&mut self, reg: Reg, arg: W) {
+            if self.decimal {
+                  return self.handle_decimal(reg, arg, false);
+            }
+            let (value, flag) = add_words(self.reg(reg), arg, self.mode);
+            self.CC = flag;
+            *self.reg_mut(reg) = value;
+      }
+
+      // Digit by digit BCD arithmetic. Results past the largest decimal the word
+      // holds, or below zero, wrap and set CARRY whatever the arithmetic mode.
+      fn handle_decimal(&mut self, reg: Reg, arg: W, subtract: bool) {
+            let (mut lhs, mut rhs) = (self.reg(reg).to_usize(), arg.to_usize());
+            let mut digits = W::MAX.to_usize();
+            let (mut result, mut shift, mut carry) = (0, 0, false);
+
+            while digits > 0 {
+                  let (x, y, c) = ((lhs & 0xF) as isize, (rhs & 0xF) as isize, carry as isize);
+                  let mut digit = if subtract { x - y - c } else { x + y + c };
+                  carry = !(0..=9).contains(&digit);
+                  if carry {
+                        digit += if subtract { 10 } else { -10 };
+                  }
+                  result |= (digit as usize & 0xF) << shift;
+                  lhs >>= 4;
+                  rhs >>= 4;
+                  digits >>= 4;
+                  shift += 4;
+            }
+
+            self.CC = if carry {
+                  Flag::CARRY
+            } else if result == 0 {
+                  Flag::ZERO
+            } else {
+                  Flag::DEFAULT
+            };
+            *self.reg_mut(reg) = W::from_usize(result);
+      }
+
+      // Products that do not fit set OVERFLOW and store what the arithmetic
+      // mode says, like ADD
+      fn handle_mul(&mut self) {
+            let (a, b) = (self.A.to_usize(), self.B.to_usize());
+            let product = a.saturating_mul(b);
+            if product > W::MAX.to_usize() {
+                  self.CC = Flag::OVERFLOW;
+                  self.A = match self.mode {
+                        ArithmeticMode::Legacy     => self.A,
+                        ArithmeticMode::Wrapping   => W::from_usize(a.wrapping_mul(b)),
+                        ArithmeticMode::Saturating => W::MAX,
+                  };
+            } else {
+                  self.CC = if product == 0 { Flag::ZERO } else { Flag::DEFAULT };
+                  self.A = W::from_usize(product);
+            }
+      }
+
+      // Dividing by zero sets DIVZERO and leaves A and B unchanged
+      fn handle_div(&mut self) {
+            let (a, b) = (self.A.to_usize(), self.B.to_usize());
+            if b == 0 {
+                  self.CC = Flag::DIVZERO;
+                  return;
+            }
+            self.A = W::from_usize(a / b);
+            self.B = W::from_usize(a % b);
+            self.CC = if a / b == 0 { Flag::ZERO } else { Flag::DEFAULT };
+      }
+
+      fn handle_sub(&mut self, reg: Reg, arg: W) {
+            if self.decimal {
+                  return self.handle_decimal(reg, arg, true);
+            }
+            let (value, flag) = sub_words(self.reg(reg), arg, self.mode);
+            self.CC = flag;
+            *self.reg_mut(reg) = value;
+      }
+}
+
+fn flag_from_word<W: Word>(word: W) -> Result<Flag, VmError> {
+      let code = word.to_usize();
+      match Flag::from_code(code as u8) {
+            Some(flag) if code <= 255 => Ok(flag),
+            _ => Err(VmError::InvalidFlag(code)),
+      }
+}
+
+// Builds a VM for the program, runs it to completion and returns the final registers
+pub fn run(program: Vec<Either<u8, Instr>>) -> Result<Registers, VmError> {
+      let mut vm = VM::new(program);
+      vm.execute()?;
+      Ok(vm.registers())
+}
+
+// Runs the program and returns what it wrote with OUTA as text, replacing
+// invalid UTF-8 with U+FFFD
+pub fn run_capturing_output(program: Vec<Either<u8, Instr>>) -> Result<String, VmError> {
+      let mut vm = VM::new(program);
+      vm.execute()?;
+      Ok(String::from_utf8_lossy(vm.output()).into_owned())
+}
+
+// Like `run`, but a program halting with a nonzero exit code is an error, so
+// test programs can report their own failures
+pub fn run_expecting_success(program: Vec<Either<u8, Instr>>) -> Result<Registers, VmError> {
+      let mut vm = VM::new(program);
+      vm.execute()?;
+      match vm.exit_code() {
+            Some(code) if code != 0 => Err(VmError::NonzeroExit(code)),
+            _                       => Ok(vm.registers()),
+      }
+}
+
+#[cfg(test)]
+mod tests {
+      use super::*;
+      use assembler::{assemble, assemble_with_symbols};
+      use program::Program;
+      use testgen;
+    
+      #[test]
+      fn initialize_new_vm() {
+            let program = vec![Right(Instr::HALT)];
+            let program_len = program.len();
+            let vm = VM::new(program);
+            assert_eq!(vm.A, 0);
+            assert_eq!(vm.B, 0);
+            assert_eq!(vm.X, 0);
+            assert_eq!(vm.Y, 0);
+            assert_eq!(vm.SP, 255);
+            assert_eq!(vm.CC, Flag::DEFAULT);
+            assert_eq!(vm.IP, program_len);
+
+            let mut size = 0;
+            for x in vm.mem.iter() {
+                  size += 1;
+                  assert_eq!(*x, 0);
+            }
+            assert_eq!(size, 256);
+      }
+
+      #[test]
+      fn memory_and_stack_constants() {
+            assert_eq!((MEM_SIZE, STACK_BASE), (256, 255));
+            let vm = VM::new(vec![]);
+            assert_eq!((vm.mem.len(), vm.SP), (MEM_SIZE, STACK_BASE));
+            assert_eq!(VM::<u16>::with_word(vec![]).SP, STACK_BASE);
+            assert_eq!(VM::builder(vec![]).stack_direction(StackDirection::Up).build().SP, 0);
+      }
+
+      #[test]
+      fn running_off_the_end_of_the_program() {
+            let mut vm = VM::new(vec![]);
+            assert_eq!(vm.halt_reason(), None);
+            vm.execute().unwrap();
+            assert_eq!((vm.halt_reason(), vm.exit_code(), vm.pc()), (Some(HaltReason::EndOfProgram), Some(0), 0));
+            assert_eq!(vm.registers(), Registers { A: 0, B: 0, X: 0, Y: 0, SP: 255, CC: Flag::DEFAULT });
+            assert_eq!(vm.cycles(), 0);
+
+            let mut data = VM::new(vec![Left(1), Left(2)]);
+            assert_eq!(data.step(), Ok(StepResult::Halted { code: 0 }));
+            assert_eq!(data.halt_reason(), Some(HaltReason::EndOfProgram));
+            assert_eq!(data.registers(), vm.registers());
+
+            let mut halted = VM::new(Program::new().halt().build());
+            halted.execute().unwrap();
+            assert_eq!(halted.halt_reason(), Some(HaltReason::Instruction));
+      }
+
+      #[test]
+      fn decoding_instructions() {
+            // A trailing PUSHi with its operand missing
+            let mut program = assemble("ADDA #5\nMOV X, A\n.byte 7").unwrap();
+            program.insert(0, Right(Instr::PUSHi));
+            let vm = VM::new(program);
+            assert_eq!(vm.decode(0), Ok(DecodedInstr { pc: 0, instr: Instr::ADDA, operands: vec![5] }));
+            assert_eq!(vm.decode(2), Ok(DecodedInstr { pc: 2, instr: Instr::MOV, operands: vec![2, 0] }));
+            assert_eq!(vm.decode(5), Err(VmError::ExpectedInstruction { pc: 5 }));
+            assert_eq!(vm.decode(6), Err(VmError::ExpectedImmediate { pc: 7 }));
+            assert_eq!(vm.decode(7), Err(VmError::PcOutOfBounds { pc: 7 }));
+      }
+
+      #[test]
+      fn decoding_whole_programs() {
+            let program = assemble("SETA #3\nMEMCPY 0x10, 0x20, #2\nJMPW 0x1234\nHALT").unwrap();
+            let entry = |pc, instr, operands: &[u8]| DecodedInstr { pc, instr, operands: operands.to_vec() };
+            assert_eq!(decoded_program(&program), Ok(vec![entry(0, Instr::SETA, &[3]),
+                                                          entry(2, Instr::MEMCPY, &[0x10, 0x20, 2]),
+                                                          entry(6, Instr::JMPW, &[0x12, 0x34]),
+                                                          entry(9, Instr::HALT, &[])]));
+            assert_eq!(decoded_program(&[]), Ok(vec![]));
+
+            let data = assemble("SETA #3\n.byte 7").unwrap();
+            assert_eq!(decoded_program(&data), Err(DecodeError::ExpectedOpcode { offset: 2 }));
+            let truncated = vec![Right(Instr::MOV), Left(1), Right(Instr::MOV)];
+            assert_eq!(decoded_program(&truncated), Err(DecodeError::MissingOperand { offset: 0, instr: Instr::MOV }));
+      }
+
+      #[test]
+      fn capping_program_length() {
+            let mut vm = VM::builder(vec![]).max_program_len(4).build();
+            let long = Program::new().set_a(1).set_b(2).halt().build();
+            assert_eq!(vm.load_program(long), Err(VmError::ProgramTooLarge { len: 5, max: 4 }));
+
+            vm.load_program(Program::new().set_a(1).add_a(2).build()).unwrap();
+            vm.execute().unwrap();
+            assert_eq!(vm.A, 3);
+
+            let mut uncapped = VM::new(vec![]);
+            uncapped.load_program(Program::new().set_a(1).set_b(2).halt().build()).unwrap();
+            uncapped.execute().unwrap();
+            assert_eq!((uncapped.A, uncapped.B), (1, 2));
+      }
+
+      #[test]
+      fn poisoned_registers() {
+            let vm = VM::builder(Program::new().halt().build()).poison_registers(0xAA).build();
+            assert_eq!(vm.registers(), Registers { A: 0xAA, B: 0xAA, X: 0xAA, Y: 0xAA, SP: 255, CC: Flag::DEFAULT });
+
+            let mut vm = VM::builder(Program::new().set_a(1).add_b(1).halt().build()).poison_registers(0xAA).build();
+            vm.execute().unwrap();
+            assert_eq!((vm.A, vm.B, vm.X), (1, 0xAB, 0xAA));
+            assert_eq!(VM::builder(vec![]).build().A, 0);
+      }
+
+      #[test]
+      fn pc_overflow_behaviors() {
+            let program = Program::new().add_a(1).build();
+
+            let mut vm = VM::builder(program.clone()).pc_overflow(PcOverflow::Halt).build();
+            vm.execute().unwrap();
+            assert_eq!((vm.A, vm.halt_reason()), (1, Some(HaltReason::EndOfProgram)));
+
+            let mut vm = VM::builder(program.clone()).pc_overflow(PcOverflow::Error).build();
+            assert_eq!(vm.execute(), Err(VmError::PcOutOfBounds { pc: 2 }));
+            assert_eq!((vm.A, vm.halt_reason()), (1, None));
+
+            let mut vm = VM::builder(program).pc_overflow(PcOverflow::Wrap).build();
+            vm.execute_until(Some(50), |vm| vm.A == 5).unwrap();
+            assert_eq!(vm.step(), Ok(StepResult::Branched { to: 0 }));
+            assert_eq!((vm.A, vm.pc(), vm.cycles()), (5, 0, 5));
+
+            let mut empty = VM::builder(vec![]).pc_overflow(PcOverflow::Wrap).build();
+            empty.execute().unwrap();
+            assert_eq!(empty.halt_reason(), Some(HaltReason::EndOfProgram));
+      }
+
+      #[test]
+      fn adding_to_registers() {
+            let add_to_a = vec![Left(10), Right(Instr::ADDA)];
+            let add_to_b = vec![Left(10), Right(Instr::ADDB)];
+            let add_to_x = vec![Left(10), Right(Instr::ADDX)];
+            let add_to_y = vec![Left(10), Right(Instr::ADDY)];
+
+            let mut vm = VM::new(add_to_a);
+            vm.execute().unwrap();
+            assert_eq!(10, vm.A);
+
+            vm = VM::new(add_to_b);
+            vm.execute().unwrap();
+            assert_eq!(10, vm.B);
+
+            vm = VM::new(add_to_x);
+            vm.execute().unwrap();
+            assert_eq!(10, vm.X);
+
+            vm = VM::new(add_to_y);
+            vm.execute().unwrap();
+            assert_eq!(10, vm.Y);
+      }
+
+      #[test]
+      fn flag_setting_on_addition_to_register() {
+            let carry = vec![Left(255), Right(Instr::ADDA), Left(1), Right(Instr::ADDA)];
+            let zero = vec![Left(0), Right(Instr::ADDA)];
+            let default = vec![Left(1), Right(Instr::ADDA)];
+
+            let mut vm = VM::new(carry);
+            vm.execute().unwrap();
+            assert_eq!(vm.CC, Flag::CARRY);
+
+            vm = VM::new(zero);
+            vm.execute().unwrap();
+            assert_eq!(vm.CC, Flag::ZERO);
+
+            vm = VM::new(default);
+            vm.execute().unwrap();
+            assert_eq!(vm.CC, Flag::DEFAULT);
+    }
+
+      #[test]
+      fn signed_overflow_and_carry() {
+            let cases = vec![
+                  (Program::new().set_a(127).add_a(1), 128, Flag::OVERFLOW),
+                  (Program::new().set_a(255).add_a(1), 0,   Flag::CARRY),
+                  (Program::new().set_a(64).add_a(64), 128, Flag::OVERFLOW),
+                  (Program::new().set_a(128).add_a(128), 0, Flag::OVERFLOW),
+                  (Program::new().set_a(200).add_a(100), 44, Flag::CARRY),
+                  (Program::new().set_a(128).sub_a(1), 127, Flag::OVERFLOW),
+                  (Program::new().set_a(0).sub_a(1),   255, Flag::CARRY),
+                  (Program::new().set_a(0).sub_a(128), 128, Flag::OVERFLOW),
+                  (Program::new().set_a(5).sub_a(3),   2,   Flag::DEFAULT),
+            ];
+
+            for (program, value, flag) in cases {
+                  let mut vm = VM::new(program.halt().build());
+                  vm.set_arithmetic_mode(ArithmeticMode::Wrapping);
+                  vm.execute().unwrap();
+                  assert_eq!((vm.A, vm.CC.clone()), (value, flag));
+            }
+      }
+
+      #[test]
+      fn sixteen_bit_words_overflow_at_65535() {
+            let mut vm = VM::<u16>::with_word(Program::new().set_a(255).add_a(1).halt().build());
+            vm.execute().unwrap();
+            assert_eq!(vm.A, 256);
+            assert_eq!(vm.CC, Flag::DEFAULT);
+
+            let mut wide = VM::<u16>::with_word(Program::new().lda(0).add_a(1).sta(1).add_a(1).halt().build());
+            wide.write_mem(0, 65534).unwrap();
+            wide.execute().unwrap();
+            assert_eq!(wide.read_mem(1), Some(65535));
+            assert_eq!(wide.CC, Flag::CARRY);
+            assert_eq!(wide.A, 65535);
+
+            // Wide index registers can point past the end of memory
+            let mut vm = VM::<u16>::with_word(Program::new().sta_x().halt().build());
+            vm.X = 300;
+            assert_eq!(vm.execute().unwrap_err().cause(), &VmError::MemoryOutOfBounds { addr: 300 });
+      }
+
+      #[test]
+      fn subtracting_from_registers() {
+            let sub_from_a = vec![Left(10), Right(Instr::SUBA), Left(42), Right(Instr::ADDA)];
+            let sub_from_b = vec![Left(10), Right(Instr::SUBB), Left(42), Right(Instr::ADDB)];
+            let sub_from_x = vec![Left(10), Right(Instr::SUBX), Left(42), Right(Instr::ADDX)];
+            let sub_from_y = vec![Left(10), Right(Instr::SUBY), Left(42), Right(Instr::ADDY)];
+
+            let mut vm = VM::new(sub_from_a);
+            vm.execute().unwrap();
+            assert_eq!(32, vm.A);
+
+            vm = VM::new(sub_from_b);
+            vm.execute().unwrap();
+            assert_eq!(32, vm.B);
+
+            vm = VM::new(sub_from_x);
+            vm.execute().unwrap();
+            assert_eq!(32, vm.X);
+
+            vm = VM::new(sub_from_y);
+            vm.execute().unwrap();
+            assert_eq!(32, vm.Y);
+      }
+
+      #[test]
+      fn setting_flags_on_subtraction() {
+            let borrow = vec![Left(10), Right(Instr::SUBA)];
+            let zero   = vec![Left(10), Right(Instr::SUBA), Left(10), Right(Instr::ADDA)];
+
+            let mut vm = VM::new(borrow);
+            vm.execute().unwrap();
+            assert_eq!(vm.CC, Flag::CARRY);
+
+            vm = VM::new(zero);
+            vm.execute().unwrap();
+            assert_eq!(vm.CC, Flag::ZERO);
+      }
+
+      #[test]
+      fn pushing_to_stack() {
+            let push_immediate = vec![Left(10), Right(Instr::PUSHi)];
+            let push_from_a = vec![Right(Instr::PUSHA), Left(10), Right(Instr::ADDA)];
+            let push_from_b = vec![Right(Instr::PUSHB), Left(10), Right(Instr::ADDB)];
+            let push_from_x = vec![Right(Instr::PUSHX), Left(10), Right(Instr::ADDX)];
+            let push_from_y = vec![Right(Instr::PUSHY), Left(10), Right(Instr::ADDY)];
+
+            let mut vm = VM::new(push_immediate);
+            vm.execute().unwrap();
+            assert_eq!(vm.mem[vm.SP+1], 10);
+
+            vm = VM::new(push_from_a);
+            vm.execute().unwrap();
+            assert_eq!(vm.mem[vm.SP + 1], vm.A);
+
+            vm = VM::new(push_from_b);
+            vm.execute().unwrap();
+            assert_eq!(vm.mem[vm.SP + 1], vm.B);
+
+            vm = VM::new(push_from_x);
+            vm.execute().unwrap();
+            assert_eq!(vm.mem[vm.SP + 1], vm.X);
+
+            vm = VM::new(push_from_y);
+            vm.execute().unwrap();
+            assert_eq!(vm.mem[vm.SP + 1], vm.Y);
+      }
+
+      #[test]
+      fn popping_from_stack() {
+            let pop_to_a = vec![Right(Instr::POPA), Left(42), Right(Instr::PUSHi)];
+            let pop_to_b = vec![Right(Instr::POPB), Left(32), Right(Instr::PUSHi)];
+            let pop_to_x = vec![Right(Instr::POPX), Left(22), Right(Instr::PUSHi)];
+            let pop_to_y = vec![Right(Instr::POPY), Left(12), Right(Instr::PUSHi)];
+
+            let mut vm = VM::new(pop_to_a);
+            vm.execute().unwrap();
+            assert_eq!(vm.A, 42);
+
+            vm = VM::new(pop_to_b);
+            vm.execute().unwrap();
+            assert_eq!(vm.B, 32);
+
+            vm = VM::new(pop_to_x);
+            vm.execute().unwrap();
+            assert_eq!(vm.X, 22);
+
+            vm = VM::new(pop_to_y);
+            vm.execute().unwrap();
+            assert_eq!(vm.Y, 12);
+      }
+
+      #[test]
+      fn injecting_faults() {
+            // 0: SETA 1, 2: PUSHA, 3: POPB, 4: HALT
+            let program = Program::new().set_a(1).push_a().pop_b().halt().build();
+            let mut vm = VM::new(program.clone());
+            vm.inject_fault(2, VmError::StackUnderflow);
+            let err = vm.execute().unwrap_err();
+            assert_eq!(err, VmError::Context { pc: 3, instr: Instr::POPB, error: Box::new(VmError::StackUnderflow) });
+            assert_eq!((vm.cycles(), vm.B), (2, 0));
+
+            // The fault fires once and execution resumes after the faulted POPB
+            vm.execute().unwrap();
+            assert_eq!(vm.B, 0);
+            assert_eq!(run(program).unwrap().B, 1);
+      }
+
+      #[test]
+      fn executing_until_a_condition() {
+            let program = Program::new().set_a(0).add_a(1).jmp(2).build();
+            let mut vm = VM::new(program);
+            vm.execute_until(None, |vm| vm.A == 5).unwrap();
+            assert_eq!(vm.A, 5);
+
+            assert_eq!(vm.execute_until(Some(100), |vm| vm.A == 0), Err(VmError::InstructionLimitExceeded));
+            assert_eq!(vm.cycles(), 10 + 100);
+
+            let mut vm = VM::new(Program::new().set_a(1).halt().build());
+            vm.execute_until(Some(10), |_| false).unwrap();
+            assert_eq!(vm.exit_code(), Some(0));
+      }
+
+      #[test]
+      fn transferring_flags() {
+            let program = assemble("SETA #5\nCMPA #5\nTFA\nSTA 0x40\n\
+                                    SETA #4\nTAF\nBRZ fail\n\
+                                    LDA 0x40\nTAF\nBRZ pass\n\
+                                    fail: HALT #1\npass: HALT").unwrap();
+            let mut vm = VM::new(program);
+            vm.execute().unwrap();
+            assert_eq!(vm.read_mem(0x40), Some(Flag::ZERO.code()));
+            assert_eq!((vm.exit_code(), vm.CC.clone()), (Some(0), Flag::ZERO));
+
+            let err = run(Program::new().set_a(9).taf().build()).unwrap_err();
+            assert_eq!(err.cause(), &VmError::InvalidFlag(9));
+
+            // The low byte alone would be ZERO's code
+            let mut wide = VM::<u16>::with_word(Program::new().taf().halt().build());
+            wide.A = 0x0101;
+            assert_eq!(wide.execute().unwrap_err().cause(), &VmError::InvalidFlag(0x0101));
+      }
+
+      #[test]
+      fn detecting_stack_corruption() {
+            let pushes = |n| (0..n).fold(Program::new(), |p, x| p.push(x)).halt().build();
+
+            let mut vm = VM::builder(pushes(4)).stack_canary(4).build();
+            vm.execute().unwrap();
+            assert_eq!(vm.read_mem(251), Some(CANARY));
+
+            let mut vm = VM::builder(pushes(5)).stack_canary(4).build();
+            assert_eq!(vm.execute(), Err(VmError::StackCorruption { addr: 251 }));
+
+            let stray = Program::new().set_a(1).sta(10).halt().build();
+            let mut vm = VM::builder(stray).stack_direction(StackDirection::Up).stack_canary(10).build();
+            assert_eq!(vm.execute(), Err(VmError::StackCorruption { addr: 10 }));
+
+            let mut vm = VM::builder(pushes(5)).build();
+            vm.execute().unwrap();
+
+            // Reported by the step that overwrites it, not only on halting
+            let mut vm = VM::builder(pushes(5)).stack_canary(4).build();
+            vm.add_breakpoint(10);
+            assert_eq!(vm.execute(), Err(VmError::StackCorruption { addr: 251 }));
+            assert_eq!(vm.pc(), 10);
+
+            let mut vm = VM::builder(pushes(5)).stack_canary(4).build();
+            for _ in 0..4 {
+                  vm.step().unwrap();
+            }
+            assert_eq!(vm.step(), Err(VmError::StackCorruption { addr: 251 }));
+      }
+
+      #[test]
+      fn transferring_the_stack_pointer() {
+            let program = Program::new().push(1).push(2).push(3).tsa().halt().build();
+            let mut vm = VM::new(program);
+            vm.execute().unwrap();
+            assert_eq!(vm.A, 252);
+            assert_eq!(vm.SP, 252);
+
+            // Dropping two entries by moving SP back up
+            let program = Program::new().push(1).push(2).push(3).tsa().add_a(2).tas().pop_b().halt().build();
+            let mut vm = VM::new(program);
+            vm.execute().unwrap();
+            assert_eq!(vm.SP, 255);
+            assert_eq!(vm.B, 1);
+
+            let mut vm = VM::<u16>::with_word(Program::new().tas().halt().build());
+            vm.A = 300;
+            assert_eq!(vm.execute().unwrap_err().cause(), &VmError::MemoryOutOfBounds { addr: 300 });
+            assert_eq!(vm.SP, 255);
+      }
+
+      #[test]
+      fn setting_registers() {
+            let set_a = vec![Left(10), Right(Instr::SETA)];
+            let set_b = vec![Left(20), Right(Instr::SETB)];
+            let set_x = vec![Left(30), Right(Instr::SETX)];
             let set_y = vec![Left(40), Right(Instr::SETY)];
 
-            let mut vm = VM::new(set_a);
-            vm.execute();
+            let mut vm = VM::new(set_a);
+            vm.execute().unwrap();
+            assert_eq!(vm.A, 10);
+
+            vm = VM::new(set_b);
+            vm.execute().unwrap();
+            assert_eq!(vm.B, 20);
+
+            vm = VM::new(set_x);
+            vm.execute().unwrap();
+            assert_eq!(vm.X, 30);
+
+            vm = VM::new(set_y);
+            vm.execute().unwrap();
+            assert_eq!(vm.Y, 40);
+      } 
+
+      #[test]
+      fn filling_memory() {
+            let fill = vec![Left(16), Left(0x40), Right(Instr::FILL), Left(0xAB), Right(Instr::SETA)];
+            let mut vm = VM::new(fill);
+            vm.execute().unwrap();
+
+            for (addr, byte) in vm.dump().iter().enumerate() {
+                  if (0x40..0x50).contains(&addr) {
+                        assert_eq!(*byte, 0xAB);
+                  } else {
+                        assert_eq!(*byte, 0);
+                  }
+            }
+
+            let out_of_bounds = vec![Left(16), Left(0xF8), Right(Instr::FILL)];
+            vm = VM::new(out_of_bounds);
+            assert_eq!(vm.execute().unwrap_err().cause(), &VmError::MemoryOutOfBounds { addr: 0x107 });
+      }
+
+      #[test]
+      fn diffing_snapshots() {
+            let program = Program::new().set_a(1).sta(0x40).halt().build();
+            let mut vm = VM::new(program.clone());
+            vm.execute().unwrap();
+            let before = vm.snapshot();
+
+            let mut other = VM::new(program);
+            other.execute().unwrap();
+            other.B = 7;
+            other.write_mem(0x41, 9).unwrap();
+            let after = other.snapshot();
+
+            assert_eq!(before.diff(&after), vec![StateDiff::Register { reg: Reg::B, old: 0, new: 7 },
+                                                 StateDiff::Memory { addr: 0x41, old: 0, new: 9 }]);
+            assert_eq!(after.diff(&after), vec![]);
+      }
+
+      #[test]
+      fn hashing_final_state() {
+            let program = Program::new().set_a(10).add_a(5).sta(0x40).push_a().halt().build();
+            let mut vm = VM::new(program.clone());
+            vm.execute().unwrap();
+            assert_eq!(vm.state_hash(), 2614176416562436912);
+
+            let mut same = VM::new(program);
+            same.execute().unwrap();
+            assert_eq!(same.state_hash(), vm.state_hash());
+            same.write_mem(0x41, 1).unwrap();
+            assert!(same.state_hash() != vm.state_hash());
+      }
+
+      // Golden hashes for a program touching arithmetic and its flags, the
+      // stack, block memory operations, input and the random number
+      // generator. They must match on every platform.
+      #[test]
+      fn deterministic_state_hashes() {
+            let src = "SETA #200\n\
+                       ADDA #100\n\
+                       PUSHA\n\
+                       SETX #7\n\
+                       SETY #6\n\
+                       CALL mul\n\
+                       STA 0x20\n\
+                       FILL 0x30, #4\n\
+                       MEMCPY 0x30, 0x40, #4\n\
+                       INA\n\
+                       STA 0x50\n\
+                       RND\n\
+                       STA 0x51\n\
+                       SETX #10\n\
+                       loop: SUBX #1\n\
+                       STA (X)\n\
+                       CMPX #0\n\
+                       BRGT loop\n\
+                       POPB\n\
+                       HALT\n\
+                       .include \"mul\"";
+            let program = assemble(src).unwrap();
+            let mut vm = VM::builder(program.clone()).input(&[42]).rng_seed(0xDEAD_BEEF).build();
+            vm.execute().unwrap();
+            assert_eq!((vm.A, vm.B), (81, 200));
+            vm.assert_mem(&[(0x20, 42), (0x43, 42), (0x50, 42)]);
+            assert_eq!(vm.state_hash(), 8434023250933713654);
+
+            let mut wide = VM::<u16>::with_word(program);
+            wide.feed_input(&[42]);
+            wide.execute().unwrap();
+            // 200 + 100 does not carry in a 16-bit word
+            assert_eq!((wide.B, wide.dump()[0x20]), (300, 42));
+            assert_eq!(wide.state_hash(), 13377811452668088914);
+      }
+
+      #[test]
+      fn nibble_operations() {
+            let program = Program::new().set_b(0x12).swapn(1).halt().build();
+            let mut vm = VM::new(program);
+            vm.execute().unwrap();
+            assert_eq!(vm.B, 0x21);
+            assert_eq!(vm.CC, Flag::DEFAULT);
+
+            let program = Program::new().set_a(0xA5).set_x(0xA5)
+                                        .andlo(0).andhi(2)
+                                        .halt().build();
+            let mut vm = VM::new(program);
+            vm.execute().unwrap();
+            assert_eq!((vm.A, vm.X), (0x05, 0xA0));
+
+            let mut vm = VM::new(Program::new().set_y(0xF0).andlo(3).halt().build());
+            vm.execute().unwrap();
+            assert_eq!((vm.Y, vm.CC.clone()), (0, Flag::ZERO));
+
+            let mut wide = VM::<u16>::with_word(Program::new().swapn(0).halt().build());
+            wide.A = 0x1234;
+            wide.execute().unwrap();
+            assert_eq!(wide.A, 0x1243);
+      }
+
+      #[test]
+      fn counting_bits() {
+            let mut vm = VM::new(Program::new().set_a(0xFF).popcnt().halt().build());
+            vm.execute().unwrap();
+            assert_eq!((vm.A, vm.CC.clone()), (8, Flag::DEFAULT));
+
+            let mut vm = VM::new(Program::new().popcnt().halt().build());
+            vm.execute().unwrap();
+            assert_eq!((vm.A, vm.CC.clone()), (0, Flag::ZERO));
+
+            let mut vm = VM::new(Program::new().set_a(0b1011).parity().halt().build());
+            vm.execute().unwrap();
+            assert_eq!((vm.A, vm.CC.clone()), (0b1011, Flag::DEFAULT));
+
+            let mut vm = VM::new(Program::new().set_a(0b1001).parity().halt().build());
+            vm.execute().unwrap();
+            assert_eq!(vm.CC, Flag::ZERO);
+      }
+
+      #[test]
+      fn clearing_memory() {
+            let program = Program::new().set_a(0xAB).fill(0x40, 16).cmp_a(0xAB).clrmem(0x44, 4).halt().build();
+            let mut vm = VM::new(program);
+            vm.execute().unwrap();
+            assert_eq!(&vm.dump()[0x40..0x50], &[0xAB, 0xAB, 0xAB, 0xAB, 0, 0, 0, 0,
+                                                  0xAB, 0xAB, 0xAB, 0xAB, 0xAB, 0xAB, 0xAB, 0xAB]);
+            assert_eq!(vm.CC, Flag::ZERO);
+
+            let mut vm = VM::new(Program::new().clrmem(0xF8, 16).build());
+            assert_eq!(vm.execute().unwrap_err().cause(), &VmError::MemoryOutOfBounds { addr: 0x107 });
+      }
+
+      #[test]
+      fn copying_memory() {
+            let setup = vec![Left(3), Left(0x10), Right(Instr::FILL), Left(1), Right(Instr::SETA),
+                             Left(5), Left(0x10), Right(Instr::FILL), Left(7), Right(Instr::SETA)];
+            let copy = vec![Left(8), Left(0x80), Left(0x10), Right(Instr::MEMCPY)];
+            let mut program = copy;
+            program.extend(setup);
+
+            let mut vm = VM::new(program);
+            vm.execute().unwrap();
+            assert_eq!(&vm.dump()[0x80..0x88], &vm.dump()[0x10..0x18]);
+            assert_eq!(&vm.dump()[0x80..0x88], &[1, 1, 1, 7, 7, 0, 0, 0]);
+
+            // Overlapping copy one byte forward shifts the table up
+            let overlapping = vec![Left(4), Left(0x81), Left(0x80), Right(Instr::MEMCPY)];
+            let table = vm.dump()[0x80..0x88].to_vec();
+            vm = VM::new(overlapping);
+            for (i, byte) in table.iter().enumerate() {
+                  vm.write_mem(0x80 + i, *byte).unwrap();
+            }
+            vm.execute().unwrap();
+            assert_eq!(&vm.dump()[0x80..0x86], &[1, 1, 1, 1, 7, 0]);
+
+            let out_of_bounds = vec![Left(16), Left(0xF8), Left(0), Right(Instr::MEMCPY)];
+            vm = VM::new(out_of_bounds);
+            assert_eq!(vm.execute().unwrap_err().cause(), &VmError::MemoryOutOfBounds { addr: 0x107 });
+      }
+
+      #[test]
+      fn running_a_program() {
+            let program = vec![Left(5), Right(Instr::ADDB), Left(10), Right(Instr::ADDA)];
+            let registers = run(program).unwrap();
+            assert_eq!(registers, Registers { A: 10, B: 5, X: 0, Y: 0, SP: 255, CC: Flag::DEFAULT });
+
+            assert_eq!(run(vec![Left(2), Left(0xFF), Right(Instr::FILL)]).unwrap_err().cause(),
+                       &VmError::MemoryOutOfBounds { addr: 0x100 });
+      }
+
+      #[test]
+      fn capturing_output_as_text() {
+            let program = "hi!".bytes().fold(Program::new(), |p, byte| p.set_a(byte).out_a()).halt().build();
+            assert_eq!(run_capturing_output(program), Ok("hi!".to_string()));
+
+            let invalid = Program::new().set_a(0xFF).out_a().set_a(b'k').out_a().build();
+            assert_eq!(run_capturing_output(invalid), Ok("\u{FFFD}k".to_string()));
+      }
+
+      #[test]
+      fn nonzero_exit_codes_fail_checked_runs() {
+            let failing = assemble("SETA #3\nHALT #1\nSETA #4").unwrap();
+            assert_eq!(run_expecting_success(failing.clone()), Err(VmError::NonzeroExit(1)));
+            assert_eq!(run(failing.clone()).unwrap().A, 3);
+
+            let mut vm = VM::new(failing);
+            assert_eq!(vm.exit_code(), None);
+            vm.step().unwrap();
+            assert_eq!(vm.step(), Ok(StepResult::Halted { code: 1 }));
+            assert_eq!(vm.step(), Ok(StepResult::Halted { code: 1 }));
+            assert_eq!(vm.exit_code(), Some(1));
+
+            let passing = assemble("SETA #3\nHALT #0").unwrap();
+            assert_eq!(run_expecting_success(passing).unwrap().A, 3);
+            assert_eq!(run_expecting_success(Program::new().set_a(3).halt().build()).unwrap().A, 3);
+      }
+
+      #[test]
+      fn moving_between_registers_preserves_flags() {
+            let program = vec![Left(0), Left(1), Right(Instr::MOV), Left(1), Right(Instr::ADDA), Left(255), Right(Instr::ADDA)];
+            let mut vm = VM::new(program);
+            vm.execute().unwrap();
+            assert_eq!(vm.B, 255);
+            assert_eq!(vm.CC, Flag::CARRY);
+
+            let zero = vec![Left(3), Left(2), Right(Instr::MOV), Right(Instr::PUSHA), Left(0), Right(Instr::ADDA)];
+            vm = VM::new(zero);
+            vm.execute().unwrap();
+            assert_eq!(vm.CC, Flag::ZERO);
+
+            let invalid = vec![Left(4), Left(0), Right(Instr::MOV)];
+            vm = VM::new(invalid);
+            assert_eq!(vm.execute().unwrap_err().cause(), &VmError::InvalidRegister(4));
+      }
+
+      #[test]
+      fn exchanging_registers() {
+            let program = assemble("SETA #1\nSETB #2\nCMPA #1\nXCHG A, B\nHALT").unwrap();
+            let mut vm = VM::new(program);
+            vm.execute().unwrap();
+            assert_eq!((vm.A, vm.B, vm.SP, vm.CC.clone()), (2, 1, 255, Flag::ZERO));
+
+            let mut vm = VM::new(Program::new().set_x(9).xchg(2, 2).halt().build());
+            vm.execute().unwrap();
+            assert_eq!(vm.X, 9);
+      }
+
+      #[test]
+      fn adding_every_pair_of_bytes() {
+            for mode in [ArithmeticMode::Legacy, ArithmeticMode::Wrapping, ArithmeticMode::Saturating] {
+                  for lhs in 0..=255u8 {
+                        for rhs in 0..=255u8 {
+                              let sum = lhs as u16 + rhs as u16;
+                              let signed = lhs as i8 as i16 + rhs as i8 as i16;
+                              let flag = if !(-128..=127).contains(&signed) {
+                                    Flag::OVERFLOW
+                              } else if sum > 255 {
+                                    Flag::CARRY
+                              } else if sum as u8 == 0 {
+                                    Flag::ZERO
+                              } else {
+                                    Flag::DEFAULT
+                              };
+                              let value = match (sum > 255, mode) {
+                                    (false, _) => sum as u8,
+                                    (true, ArithmeticMode::Legacy)     => lhs,
+                                    (true, ArithmeticMode::Wrapping)   => sum as u8,
+                                    (true, ArithmeticMode::Saturating) => 255,
+                              };
+                              let expected = (value, flag);
+                              assert_eq!(add_words(lhs, rhs, mode), expected, "{} + {} in {:?}", lhs, rhs, mode);
+                        }
+                  }
+            }
+
+            assert_eq!(add_words(0xFFu16, 1, ArithmeticMode::Legacy), (0x100, Flag::DEFAULT));
+            assert_eq!(add_words(0xFFFFu16, 1, ArithmeticMode::Wrapping), (0, Flag::CARRY));
+            assert_eq!(add_words(0x7FFFu16, 1, ArithmeticMode::Wrapping), (0x8000, Flag::OVERFLOW));
+            assert_eq!(add_words(0u16, 0, ArithmeticMode::Saturating), (0, Flag::ZERO));
+      }
+
+      #[test]
+      fn subtracting_words() {
+            let cases = [
+                  (ArithmeticMode::Legacy,     0u8, 1, (0, Flag::CARRY)),
+                  (ArithmeticMode::Wrapping,   0, 1, (255, Flag::CARRY)),
+                  (ArithmeticMode::Saturating, 0, 1, (0, Flag::CARRY)),
+                  (ArithmeticMode::Legacy,     3, 9, (3, Flag::CARRY)),
+                  (ArithmeticMode::Legacy,     0x80, 1, (0x7F, Flag::OVERFLOW)),
+            ];
+            for (mode, lhs, rhs, expected) in cases {
+                  assert_eq!(sub_words(lhs, rhs, mode), expected, "{} - {} in {:?}", lhs, rhs, mode);
+            }
+
+            for mode in [ArithmeticMode::Legacy, ArithmeticMode::Wrapping, ArithmeticMode::Saturating] {
+                  assert_eq!(sub_words(42u8, 42, mode), (0, Flag::ZERO));
+                  assert_eq!(sub_words(42u8, 5, mode), (37, Flag::DEFAULT));
+                  assert_eq!(sub_words(255u8, 0, mode), (255, Flag::DEFAULT));
+            }
+            assert_eq!(sub_words(0u16, 1, ArithmeticMode::Wrapping), (0xFFFF, Flag::CARRY));
+      }
+
+      #[test]
+      fn fuzzing_random_programs() {
+            assert_eq!(testgen::fuzz_until_failure(2000, 0), None);
+      }
+
+      #[test]
+      fn memory_mapped_devices() {
+            struct Port {
+                  log: Rc<RefCell<Vec<String>>>,
+            }
+            impl Device for Port {
+                  fn read(&mut self, addr: usize) -> u8 {
+                        self.log.borrow_mut().push(format!("read {:#X}", addr));
+                        addr as u8 - 0xC0
+                  }
+                  fn write(&mut self, addr: usize, val: u8) {
+                        self.log.borrow_mut().push(format!("write {:#X} {}", addr, val));
+                  }
+            }
+
+            let program = assemble("SETA #5\nSTA 0xF0\nLDA 0xF1\nADDA 0xF0\nSTA 0x10\nHALT").unwrap();
+            let log = Rc::new(RefCell::new(Vec::new()));
+            let mut vm = VM::new(program);
+            vm.attach_device(0xF0..0xF2, Box::new(Port { log: log.clone() }));
+            vm.execute().unwrap();
+
+            assert_eq!(*log.borrow(), vec!["write 0xF0 5", "read 0xF1", "read 0xF0"]);
+            assert_eq!(vm.A, 0x31 + 0x30);
+            vm.assert_mem(&[(0xF0, 0), (0x10, 0x61)]);
+      }
+
+      #[test]
+      fn ticking_timers() {
+            let program = assemble("LDA 0xE0\nSTA 0x10\nNOP\nNOP\nNOP\nNOP\nNOP\nNOP\nLDA 0xE0\nSTA 0x11\nHALT").unwrap();
+            let mut vm = VM::new(program);
+            vm.attach_device(0xE0..0xE1, Box::new(Timer::new(2)));
+            vm.execute().unwrap();
+            // The second read comes after 8 instructions
+            vm.assert_mem(&[(0x10, 0), (0x11, 4)]);
+
+            let (program, symbols) = assemble_with_symbols("SETA #255\n\
+                                                            STA 0xE0\n\
+                                                            HALT\n\
+                                                            wrapped: SETB #7\n\
+                                                            RET").unwrap();
+            let mut vm = VM::builder(program).interrupt_handler(symbols["wrapped"]).build();
+            vm.attach_device(0xE0..0xE1, Box::new(Timer::new(1).interrupt_on_wrap()));
+            vm.step().unwrap();
+            assert_eq!(vm.step(), Ok(StepResult::Branched { to: symbols["wrapped"] }));
+            vm.execute().unwrap();
+            assert_eq!((vm.B, vm.SP, vm.pc()), (7, 255, 5));
+      }
+
+      #[test]
+      fn rotating_registers() {
+            let setup = Program::new().set_a(1).set_b(2).set_x(3).set_y(4).cmp_a(1);
+            let mut vm = VM::new(setup.clone().rotregs(0).halt().build());
+            vm.execute().unwrap();
+            assert_eq!((vm.A, vm.B, vm.X, vm.Y, vm.CC.clone()), (4, 1, 2, 3, Flag::ZERO));
+
+            vm = VM::new(setup.clone().rotregs(1).halt().build());
+            vm.execute().unwrap();
+            assert_eq!((vm.A, vm.B, vm.X, vm.Y), (2, 3, 4, 1));
+
+            vm = VM::new(setup.rotregs(2).halt().build());
+            assert_eq!(vm.execute().unwrap_err().cause(), &VmError::InvalidDirection(2));
+      }
+
+      #[test]
+      fn conforming_to_a_reference_trace() {
+            let program = Program::new().set_a(3).sub_a(3).push_a().halt().build();
+            let state = |a, sp, cc, pc| ExpectedState { registers: Registers { A: a, B: 0, X: 0, Y: 0, SP: sp, CC: cc }, pc };
+            let trace = vec![state(3, 255, Flag::DEFAULT, 2),
+                             state(0, 255, Flag::ZERO, 4),
+                             state(0, 254, Flag::ZERO, 5),
+                             state(0, 254, Flag::ZERO, 6)];
+            VM::new(program.clone()).run_conformance(&trace).unwrap();
+
+            let mut doctored = trace.clone();
+            doctored[2].registers.SP = 255;
+            assert_eq!(VM::new(program.clone()).run_conformance(&doctored),
+                       Err(ConformanceError::Mismatch { cycle: 2, expected: doctored[2].clone(), found: trace[2].clone() }));
+
+            let mut longer = trace.clone();
+            longer.push(trace[3].clone());
+            assert_eq!(VM::new(program).run_conformance(&longer), Err(ConformanceError::HaltedEarly { cycle: 4 }));
+      }
+
+      #[test]
+      fn executing_from_a_start_index() {
+            let (program, symbols) = assemble_with_symbols("SETA #1\n\
+                                                            HALT\n\
+                                                            second: SETB #2\n\
+                                                            HALTi #7").unwrap();
+            let mut vm = VM::new(program.clone());
+            vm.execute_from(symbols["second"]).unwrap();
+            assert_eq!((vm.A, vm.B, vm.exit_code()), (0, 2, Some(7)));
+
+            vm = VM::new(program.clone());
+            assert_eq!(vm.execute_from(program.len() + 1), Err(VmError::JumpOutOfBounds { target: program.len() + 1 }));
+      }
+
+      #[test]
+      fn setting_and_clearing_bits() {
+            let mut vm = VM::new(Program::new().bset(1, 3).halt().build());
+            vm.execute().unwrap();
+            assert_eq!((vm.B, vm.CC.clone()), (0x08, Flag::DEFAULT));
+
+            vm = VM::new(Program::new().bset(1, 3).bclr(1, 3).halt().build());
+            vm.execute().unwrap();
+            assert_eq!((vm.B, vm.CC.clone()), (0, Flag::ZERO));
+
+            vm = VM::new(Program::new().bset(0, 8).halt().build());
+            assert_eq!(vm.execute().unwrap_err().cause(), &VmError::InvalidBit(8));
+      }
+
+      #[test]
+      fn storing_immediates() {
+            let program = assemble("STI 0x30, #42\nSETA #1\nLDA 0x30\nHALT").unwrap();
+            assert_eq!(&program[5..], &[Left(42), Left(0x30), Right(Instr::STI)]);
+            let mut vm = VM::new(program);
+            vm.execute().unwrap();
+            assert_eq!((vm.A, vm.read_mem(0x30)), (42, Some(42)));
+
+            let mut vm = VM::new(Program::new().sti(0x30, 1).halt().build());
+            vm.protect(0x30..0x31, Perms::ReadOnly);
+            assert_eq!(vm.execute().unwrap_err().cause(), &VmError::ProtectionFault { addr: 0x30 });
+      }
+
+      #[test]
+      fn loading_and_storing_through_a_pointer() {
+            let load = vec![Right(Instr::LDAX), Left(0x20), Right(Instr::SETX), Left(0), Right(Instr::SETA),
+                            Left(0x20), Right(Instr::STA), Left(42), Right(Instr::SETA)];
+            let mut vm = VM::new(load);
+            vm.execute().unwrap();
+            assert_eq!(vm.A, 42);
+
+            let store = vec![Left(0x30), Right(Instr::LDA), Right(Instr::STAY), Left(0x30), Right(Instr::SETY), Left(7), Right(Instr::SETA)];
+            vm = VM::new(store);
+            vm.execute().unwrap();
+            assert_eq!(vm.dump()[0x30], 7);
+            assert_eq!(vm.A, 7);
+      }
+
+      #[test]
+      fn arithmetic_modes_on_overflow() {
+            let cases = vec![
+                  (ArithmeticMode::Legacy,     vec![Left(1), Right(Instr::ADDA), Left(255), Right(Instr::SETA)], 255, Flag::CARRY),
+                  (ArithmeticMode::Legacy,     vec![Left(1), Right(Instr::SUBA)],                              0,   Flag::CARRY),
+                  (ArithmeticMode::Wrapping,   vec![Left(1), Right(Instr::ADDA), Left(255), Right(Instr::SETA)], 0,   Flag::CARRY),
+                  (ArithmeticMode::Wrapping,   vec![Left(1), Right(Instr::SUBA)],                              255, Flag::CARRY),
+                  (ArithmeticMode::Saturating, vec![Left(1), Right(Instr::ADDA), Left(255), Right(Instr::SETA)], 255, Flag::CARRY),
+                  (ArithmeticMode::Saturating, vec![Left(1), Right(Instr::SUBA)],                              0,   Flag::CARRY),
+            ];
+
+            for (mode, program, value, flag) in cases {
+                  let mut vm = VM::new(program);
+                  vm.set_arithmetic_mode(mode);
+                  vm.execute().unwrap();
+                  assert_eq!((vm.A, vm.CC.clone()), (value, flag), "mode {:?}", mode);
+            }
+
+            for &mode in &[ArithmeticMode::Legacy, ArithmeticMode::Wrapping, ArithmeticMode::Saturating] {
+                  let mut vm = VM::new(vec![Left(1), Right(Instr::SUBA), Left(1), Right(Instr::SETA)]);
+                  vm.set_arithmetic_mode(mode);
+                  vm.execute().unwrap();
+                  assert_eq!((vm.A, vm.CC.clone()), (0, Flag::ZERO), "mode {:?}", mode);
+            }
+      }
+
+      #[test]
+      fn multiply_and_divide_flags() {
+            let cases = vec![
+                  (Instr::MUL, 6, 7, (42, 7), Flag::DEFAULT),
+                  (Instr::MUL, 0, 9, (0, 9), Flag::ZERO),
+                  (Instr::MUL, 16, 16, (16, 16), Flag::OVERFLOW),
+                  (Instr::DIV, 43, 5, (8, 3), Flag::DEFAULT),
+                  (Instr::DIV, 3, 5, (0, 3), Flag::ZERO),
+                  (Instr::DIV, 9, 0, (9, 0), Flag::DIVZERO),
+            ];
+
+            for (instr, a, b, result, flag) in cases {
+                  // The preceding CMPA leaves a flag that must not survive
+                  let program = Program::new().set_a(a).set_b(b).cmp_a(200).emit(instr.clone(), &[]).halt().build();
+                  let mut vm = VM::new(program);
+                  vm.execute().unwrap();
+                  assert_eq!(((vm.A, vm.B), vm.CC.clone()), (result, flag), "{:?} {} {}", instr, a, b);
+            }
+
+            let mut vm = VM::builder(Program::new().set_a(16).set_b(17).mul().halt().build())
+                           .arithmetic_mode(ArithmeticMode::Wrapping)
+                           .build();
+            vm.execute().unwrap();
+            assert_eq!((vm.A, vm.CC.clone()), (16, Flag::OVERFLOW));
+
+            let mut wide = VM::<u16>::with_word(Program::new().set_a(200).set_b(200).mul().halt().build());
+            wide.execute().unwrap();
+            assert_eq!((wide.A, wide.CC.clone()), (40000, Flag::DEFAULT));
+      }
+
+      #[test]
+      fn decimal_mode_arithmetic() {
+            let cases = vec![
+                  (Program::new().set_a(0x09).add_a(0x01), 0x10, Flag::DEFAULT),
+                  (Program::new().set_a(0x38).add_a(0x45), 0x83, Flag::DEFAULT),
+                  (Program::new().set_a(0x95).add_a(0x07), 0x02, Flag::CARRY),
+                  (Program::new().set_a(0x99).add_a(0x01), 0x00, Flag::CARRY),
+                  (Program::new().set_a(0x10).sub_a(0x01), 0x09, Flag::DEFAULT),
+                  (Program::new().set_a(0x42).sub_a(0x42), 0x00, Flag::ZERO),
+                  (Program::new().set_a(0x00).sub_a(0x01), 0x99, Flag::CARRY),
+            ];
+
+            for (program, value, flag) in cases {
+                  let mut vm = VM::builder(program.halt().build()).decimal_mode(true).build();
+                  vm.execute().unwrap();
+                  assert_eq!((vm.A, vm.CC.clone()), (value, flag));
+            }
+
+            let mut wide = VM::<u16>::with_word(Program::new().add_a(0x01).halt().build());
+            wide.set_decimal_mode(true);
+            wide.A = 0x0999;
+            wide.execute().unwrap();
+            assert_eq!(wide.A, 0x1000);
+      }
+
+      #[test]
+      fn saving_and_restoring_context() {
+            let program = vec![Left(0x80), Right(Instr::LOADCTX),
+                               Right(Instr::PUSHA), Right(Instr::PUSHA), Left(0), Right(Instr::ADDY),
+                               Left(9), Right(Instr::SETX), Left(9), Right(Instr::SETB), Left(9), Right(Instr::SETA),
+                               Left(0x80), Right(Instr::SAVECTX),
+                               Left(4), Right(Instr::PUSHi), Left(1), Right(Instr::ADDA),
+                               Left(4), Right(Instr::SETY), Left(3), Right(Instr::SETX), Left(2), Right(Instr::SETB)];
+            let mut vm = VM::new(program);
+            vm.execute().unwrap();
+            assert_eq!(vm.registers(), Registers { A: 1, B: 2, X: 3, Y: 4, SP: 254, CC: Flag::DEFAULT });
+            assert_eq!(&vm.dump()[0x80..0x86], &[1, 2, 3, 4, Flag::DEFAULT.code(), 254]);
+
+            let corrupted = vec![Left(0x80), Right(Instr::LOADCTX), Left(0x84), Right(Instr::STA), Left(9), Right(Instr::SETA)];
+            vm = VM::new(corrupted);
+            assert_eq!(vm.execute().unwrap_err().cause(), &VmError::InvalidFlag(9));
+
+            vm = VM::new(vec![Left(0xFB), Right(Instr::SAVECTX)]);
+            assert_eq!(vm.execute().unwrap_err().cause(), &VmError::MemoryOutOfBounds { addr: 0x100 });
+      }
+
+      #[test]
+      fn stack_directions() {
+            let program = vec![Right(Instr::POPB), Right(Instr::POPA), Left(2), Right(Instr::PUSHi), Left(1), Right(Instr::PUSHi)];
+
+            let mut vm = VM::builder(program.clone()).build();
+            assert_eq!(vm.SP, 255);
+            vm.execute().unwrap();
+            assert_eq!((vm.A, vm.B, vm.SP), (2, 1, 255));
+            assert_eq!(&vm.dump()[254..], &[2, 1]);
+
+            vm = VM::builder(program).stack_direction(StackDirection::Up).build();
+            assert_eq!(vm.SP, 0);
+            vm.execute().unwrap();
+            assert_eq!((vm.A, vm.B, vm.SP), (2, 1, 0));
+            assert_eq!(&vm.dump()[..2], &[1, 2]);
+
+            vm = VM::builder(vec![Right(Instr::POPA), Left(5), Right(Instr::SETA)]).stack_direction(StackDirection::Up).build();
+            assert_eq!(vm.execute().unwrap_err().cause(), &VmError::StackUnderflow);
+            assert_eq!((vm.A, vm.SP), (5, 0));
+      }
+
+      #[test]
+      fn custom_initial_stack_pointer() {
+            let program = Program::new().push(1).push(2).push(3).pop_a().pop_b().pop_x().pop_y().halt().build();
+            let mut mem = [0; 256];
+            for x in &mut mem[201..] {
+                  *x = 7;
+            }
+            let mut vm = VM::builder(program).memory(mem).stack_pointer(200).build();
+            assert_eq!(vm.SP, 200);
+            assert_eq!(vm.execute().unwrap_err().cause(), &VmError::StackUnderflow);
+            assert_eq!((vm.A, vm.B, vm.X, vm.SP), (3, 2, 1, 200));
+            assert_eq!(&vm.dump()[198..201], &[3, 2, 1]);
+            assert!(vm.dump()[201..].iter().all(|x| *x == 7));
+      }
+
+      #[test]
+      fn errors_report_pc_and_instruction() {
+            // 0: SETA 1, 2: PUSHA, 3: POPB, 4: POPA
+            let program = Program::new().set_a(1).push_a().pop_b().pop_a().halt().build();
+            let err = run(program).unwrap_err();
+            assert_eq!(err, VmError::Context { pc: 4, instr: Instr::POPA, error: Box::new(VmError::StackUnderflow) });
+            assert_eq!(err.to_string(), "StackUnderflow at pc=4 executing POPA");
+
+            let err = run(vec![Left(0xFF), Right(Instr::LDA), Left(4), Left(0), Right(Instr::MOV)]).unwrap_err();
+            assert_eq!(err.to_string(), "InvalidRegister: 4 is not a register code at pc=0 executing MOV");
+            let boxed: Box<dyn error::Error> = Box::new(err);
+            assert!(boxed.to_string().contains("pc=0"));
+      }
+
+      #[test]
+      fn operand_slots_must_hold_bytes() {
+            // 0: ADDA with HALT where its operand belongs
+            let err = run(vec![Right(Instr::HALT), Right(Instr::ADDA)]).unwrap_err();
+            assert_eq!(err, VmError::Context { pc: 0, instr: Instr::ADDA,
+                                               error: Box::new(VmError::ExpectedImmediate { pc: 1 }) });
+
+            // 2: FILL missing its length operand at the end of the program
+            let err = run(Program::new().set_a(1).fill(0, 1).build()[1..].to_vec()).unwrap_err();
+            assert_eq!(err.cause(), &VmError::ExpectedImmediate { pc: 4 });
+      }
+
+      #[test]
+      fn skipping_on_zero() {
+            let mut vm = VM::new(assemble("CMPA #0\nSKPZ\nSETA #99\nSETB #1\nHALT").unwrap());
+            vm.execute().unwrap();
+            assert_eq!((vm.A, vm.B), (0, 1));
+
+            vm = VM::new(assemble("CMPA #1\nSKPZ\nSETA #99\nSETB #1\nHALT").unwrap());
+            vm.execute().unwrap();
+            assert_eq!((vm.A, vm.B), (99, 1));
+
+            vm = VM::new(assemble("CMPA #1\nSKPNZ\nMEMCPY 0x10, 0x20, #1\nSETB #1\nHALT").unwrap());
+            vm.step().unwrap();
+            assert_eq!(vm.step(), Ok(StepResult::Branched { to: 7 }));
+            assert_eq!(vm.last_branch_taken(), Some(true));
+
+            // Skipping the last instruction runs off the end
+            vm = VM::new(assemble("CMPA #0\nSKPZ\nSETA #99").unwrap());
+            vm.execute().unwrap();
+            assert_eq!((vm.A, vm.halt_reason()), (0, Some(HaltReason::EndOfProgram)));
+      }
+
+      #[test]
+      fn reporting_branch_outcomes() {
+            // 0: CMPA 0, 2: BRZ 5, 4: HALT, 5: CMPA 1, 7: BRZ 4, 9: JMP 4
+            let program = Program::new().cmp_a(0).brz(5).halt().cmp_a(1).brz(4).jmp(4).build();
+            let mut vm = VM::new(program);
+            vm.step().unwrap();
+            assert_eq!(vm.last_branch_taken(), None);
+            vm.step().unwrap();
+            assert_eq!(vm.last_branch_taken(), Some(true));
+            vm.step().unwrap();
+            assert_eq!(vm.last_branch_taken(), None);
+            vm.step().unwrap();
+            assert_eq!(vm.last_branch_taken(), Some(false));
+            vm.step().unwrap();
+            assert_eq!(vm.last_branch_taken(), None);
+            assert_eq!(vm.pc(), 4);
+      }
+
+      #[test]
+      fn recording_opcode_coverage() {
+            let program = assemble("SETA #3\nloop: SUBA #1\nBRZ done\nJMP loop\ndone: PUSHA\nHALT").unwrap();
+            let mut vm = VM::new(program);
+            assert!(vm.opcode_coverage().is_empty());
+            vm.execute().unwrap();
+
+            let covered: Vec<Instr> = vm.opcode_coverage().into_iter().collect();
+            assert_eq!(covered, vec![Instr::PUSHA, Instr::SUBA, Instr::BRZ, Instr::SETA, Instr::HALT, Instr::JMP]);
+      }
+
+      #[test]
+      fn patching_instructions() {
+            // 0: SETA 99, 2: ADDB 1, 4: HALT
+            let program = Program::new().set_a(99).add_b(1).halt().build();
+            let mut vm = VM::new(program);
+            vm.patch(0, Right(Instr::NOP)).unwrap();
+            vm.patch(3, Left(5)).unwrap();
+            vm.execute().unwrap();
+            assert_eq!((vm.A, vm.B), (0, 5));
+
+            let mut vm = VM::new(Program::new().set_a(99).halt().build());
+            assert_eq!(vm.patch(3, Right(Instr::NOP)), Err(VmError::InvalidPatch { addr: 3 }));
+            assert_eq!(vm.patch(1, Right(Instr::NOP)), Err(VmError::InvalidPatch { addr: 1 }));
+            assert_eq!(vm.patch(2, Right(Instr::SETA)), Err(VmError::InvalidPatch { addr: 2 }));
+            assert_eq!(vm.patch(0, Left(0)), Err(VmError::InvalidPatch { addr: 0 }));
+            vm.execute().unwrap();
+            assert_eq!(vm.A, 99);
+      }
+
+      #[test]
+      fn peeking_and_poking_memory() {
+            let mut vm = VM::new(vec![Left(0x10), Right(Instr::LDA)]);
+            vm.write_mem(0x10, 99).unwrap();
+            assert_eq!(vm.read_mem(0x10), Some(99));
+            vm.execute().unwrap();
+            assert_eq!(vm.A, 99);
+
+            assert_eq!(vm.read_mem(256), None);
+            assert_eq!(vm.write_mem(256, 1), Err(VmError::MemoryOutOfBounds { addr: 256 }));
+      }
+
+      #[test]
+      fn spilling_registers() {
+            let mut vm = VM::builder(Program::new().set_a(9).halt().build()).scratch_area(0xF0, 4).build();
+            vm.A = 42;
+            vm.spill(Reg::A, 1);
+            assert!(vm.slot_occupied(1) && !vm.slot_occupied(0));
+            assert_eq!(vm.dump()[0xF1], 42);
+
+            vm.execute().unwrap();
+            assert_eq!(vm.A, 9);
+            vm.reload(Reg::A, 1);
+            assert_eq!(vm.A, 42);
+            assert!(!vm.slot_occupied(1));
+
+            vm.spill(Reg::A, 3);
+            vm.reload(Reg::Y, 3);
+            assert_eq!(vm.Y, 42);
+      }
+
+      #[test]
+      #[should_panic(expected = "memory differs at 2 of 3 addresses:\n  [0x10] expected 1, found 0\n  [0x100] expected 0, outside memory")]
+      fn asserting_memory_reports_every_mismatch() {
+            let mut vm = VM::new(vec![]);
+            vm.load_data(0x11, &[2]).unwrap();
+            vm.assert_mem(&[(0x10, 1), (0x11, 2), (0x100, 0)]);
+      }
+
+      #[test]
+      fn loading_data_blobs() {
+            let mut vm = VM::new(vec![]);
+            vm.load_data(0x30, &[1, 2, 3, 4, 5]).unwrap();
+            assert_eq!(&vm.dump()[0x2F..0x36], &[0, 1, 2, 3, 4, 5, 0]);
+
+            assert_eq!(vm.load_data(0xFC, &[9; 5]), Err(VmError::MemoryOutOfBounds { addr: 256 }));
+            assert_eq!(vm.load_data(300, &[]), Err(VmError::MemoryOutOfBounds { addr: 300 }));
+            assert_eq!(vm.dump()[0xFC], 0);
+            vm.load_data(256, &[]).unwrap();
+      }
+
+      #[test]
+      fn stepping_through_a_branch() {
+            // 0: SETA 1, 2: SUBA 1, 4: BRZ 8, 6: SETB 9, 8: HALT
+            let program = vec![Right(Instr::HALT), Left(9), Right(Instr::SETB), Left(8), Right(Instr::BRZ),
+                               Left(1), Right(Instr::SUBA), Left(1), Right(Instr::SETA)];
+            let mut vm = VM::new(program);
+            assert_eq!(vm.step(), Ok(StepResult::Continued));
+            assert_eq!(vm.step(), Ok(StepResult::Continued));
+            assert_eq!(vm.step(), Ok(StepResult::Branched { to: 8 }));
+            assert_eq!(vm.pc(), 8);
+            assert_eq!(vm.step(), Ok(StepResult::Halted { code: 0 }));
+            assert_eq!(vm.step(), Ok(StepResult::Halted { code: 0 }));
+            assert_eq!(vm.B, 0);
+      }
+
+      #[test]
+      fn breakpoints_and_watchpoints() {
+            // 0: SETA 5, 2: STA 0x40, 4: SETB 1, 6: HALT
+            let program = vec![Right(Instr::HALT), Left(1), Right(Instr::SETB), Left(0x40), Right(Instr::STA),
+                               Left(5), Right(Instr::SETA)];
+            let mut vm = VM::new(program);
+            vm.add_breakpoint(4);
+            vm.add_watchpoint(0x40).unwrap();
+            assert_eq!(vm.add_watchpoint(256), Err(VmError::MemoryOutOfBounds { addr: 256 }));
+
+            vm.execute().unwrap();
+            assert_eq!(vm.pc(), 4);
+            assert_eq!(vm.dump()[0x40], 5);
+            assert_eq!(vm.step(), Ok(StepResult::Hit(Hit::Breakpoint(4))));
+            assert_eq!(vm.step(), Ok(StepResult::Continued));
+            assert_eq!(vm.B, 1);
+            assert_eq!(vm.step(), Ok(StepResult::Halted { code: 0 }));
+      }
+
+      #[test]
+      fn comparing_stack_values() {
+            let cases = vec![(3, 3, Flag::ZERO), (2, 5, Flag::NEGATIVE), (5, 2, Flag::DEFAULT)];
+
+            for (second, first, flag) in cases {
+                  let program = vec![Right(Instr::CMPSTK), Left(first), Right(Instr::PUSHi), Left(second), Right(Instr::PUSHi)];
+                  let mut vm = VM::new(program);
+                  vm.execute().unwrap();
+                  assert_eq!(vm.CC, flag);
+                  assert_eq!(vm.SP, 255);
+            }
+      }
+
+      #[test]
+      fn taking_a_halted_vm_apart() {
+            let program = vec![Right(Instr::HALT), Right(Instr::OUTA), Left(0x10), Right(Instr::STA),
+                               Right(Instr::OUTA), Left(1), Right(Instr::ADDA), Right(Instr::OUTA), Left(0), Right(Instr::ADDA)];
+            let mut vm = VM::new(program);
+            vm.execute().unwrap();
+            assert_eq!(vm.output(), &[0, 1, 1]);
+
+            let parts = vm.into_parts();
+            assert_eq!(parts.registers.A, 1);
+            assert_eq!(parts.registers.CC, Flag::DEFAULT);
+            assert_eq!(parts.mem[0x10], 1);
+            assert_eq!(parts.output, vec![0, 1, 1]);
+      }
+
+      #[test]
+      fn composite_branches_after_compare() {
+            // 0: SETA a, 2: CMPA 5, 4: BRxx 9, 6: SETB 1, 8: HALT, 9: SETB 2
+            let branch = |a: u8, instr: Instr| {
+                  let program = vec![Left(2), Right(Instr::SETB), Right(Instr::HALT), Left(1), Right(Instr::SETB),
+                                     Left(9), Right(instr), Left(5), Right(Instr::CMPA), Left(a), Right(Instr::SETA)];
+                  run(program).unwrap().B == 2
+            };
+
+            assert!(branch(5, Instr::BRLE));
+            assert!(branch(4, Instr::BRLE));
+            assert!(!branch(6, Instr::BRLE));
+
+            assert!(!branch(5, Instr::BRGT));
+            assert!(!branch(4, Instr::BRGT));
+            assert!(branch(6, Instr::BRGT));
+      }
+
+      #[test]
+      fn branching_past_256_entries() {
+            let padding = ".byte 0\n".repeat(300);
+            let src = format!("SETA #1\nSUBA #1\nBRZW far\n{}far: SETB #7\nJMPW 0x1000\n", padding);
+            let (program, symbols) = assemble_with_symbols(&src).unwrap();
+            assert_eq!(symbols["far"], 307);
+            assert_eq!(&program[program.len() - 7..program.len() - 4], &[Left(51), Left(1), Right(Instr::BRZW)]);
+
+            let mut vm = VM::new(program);
+            let err = vm.execute().unwrap_err();
+            assert_eq!(vm.B, 7);
+            assert_eq!(err, VmError::Context { pc: 309, instr: Instr::JMPW, error: Box::new(VmError::JumpOutOfBounds { target: 0x1000 }) });
+
+            let program = Program::new().jmp_wide(4).halt().set_a(3).halt().build();
+            assert_eq!(run(program).unwrap().A, 3);
+      }
+
+      #[test]
+      fn jumping_through_a_register() {
+            // 0: SETX 7, 2: JMPX, 3: SETA 1, 5: JMP 9, 7: SETB 2, 9: HALT
+            let program = vec![Right(Instr::HALT), Left(2), Right(Instr::SETB), Left(9), Right(Instr::JMP),
+                               Left(1), Right(Instr::SETA), Right(Instr::JMPX), Left(7), Right(Instr::SETX)];
+            let mut vm = VM::new(program);
+            assert_eq!(vm.step(), Ok(StepResult::Continued));
+            assert_eq!(vm.step(), Ok(StepResult::Branched { to: 7 }));
+            vm.execute().unwrap();
+            assert_eq!((vm.A, vm.B), (0, 2));
+
+            vm = VM::new(vec![Right(Instr::JMPX), Left(200), Right(Instr::SETX)]);
+            assert_eq!(vm.execute().unwrap_err().cause(), &VmError::JumpOutOfBounds { target: 200 });
+      }
+
+      #[test]
+      fn previewing_a_step() {
+            let program = vec![Right(Instr::PUSHA), Left(1), Right(Instr::ADDA), Left(255), Right(Instr::SETA)];
+            let mut vm = VM::new(program);
+            vm.step().unwrap();
+
+            let effect = vm.preview_step().unwrap();
+            assert_eq!(effect, StepEffect {
+                  result: StepResult::Continued,
+                  registers: vec![],
+                  sp: None,
+                  memory: vec![],
+                  flags: Some(Flag::CARRY),
+                  pc: 4,
+            });
+            assert_eq!((vm.A, vm.CC.clone(), vm.pc()), (255, Flag::DEFAULT, 2));
+
+            vm.set_arithmetic_mode(ArithmeticMode::Wrapping);
+            let effect = vm.preview_step().unwrap();
+            assert_eq!(effect.registers, vec![(Reg::A, 0)]);
+
+            vm.step().unwrap();
+            let effect = vm.preview_step().unwrap();
+            assert_eq!((effect.sp, effect.memory), (Some(254), vec![]));
+            assert_eq!(vm.SP, 255);
+      }
+
+      #[test]
+      fn previewing_leaves_devices_alone() {
+            let program = assemble("NOP\nSETA #9\nSTA 0xE0\nLDA 0xE0\nHALT").unwrap();
+            let mut vm = VM::new(program);
+            vm.attach_device(0xE0..0xE1, Box::new(Timer::new(1)));
+            vm.step().unwrap();
+            vm.preview_step().unwrap();
+            vm.step().unwrap();
+
+            // The write lands in the clone's memory, not the timer
+            let effect = vm.preview_step().unwrap();
+            assert_eq!(effect.memory, vec![(0xE0, 9)]);
+            vm.step().unwrap();
+            assert_eq!(vm.preview_step().unwrap().registers, vec![(Reg::A, 0)]);
+            vm.step().unwrap();
+            // Set to 9 then ticked once, the previews ticked nothing
             assert_eq!(vm.A, 10);
+      }
 
-            vm = VM::new(set_b);
-            vm.execute();
-            assert_eq!(vm.B, 20);
+      #[test]
+      fn disabled_instructions_trap() {
+            let program = vec![Right(Instr::HALT), Right(Instr::RND), Left(1), Right(Instr::SETA)];
+            let mut vm = VM::builder(program.clone()).disable_instruction(Instr::RND).build();
+            assert_eq!(vm.execute().unwrap_err().cause(), &VmError::IllegalInstruction(Instr::RND));
+            assert_eq!(vm.A, 1);
+
+            vm = VM::builder(program).disable_instruction(Instr::MEMCPY).build();
+            assert_eq!(vm.execute(), Ok(()));
+      }
 
-            vm = VM::new(set_x);
-            vm.execute();
-            assert_eq!(vm.X, 30);
+      #[test]
+      fn calling_subroutines() {
+            // 0: CALL 6, 2: ADDA 1, 4: OUTA, 5: HALT, 6: SETA 41, 8: PUSHi 9, 10: POPB, 11: RET
+            let program = vec![Right(Instr::RET), Right(Instr::POPB), Left(9), Right(Instr::PUSHi), Left(41), Right(Instr::SETA),
+                               Right(Instr::HALT), Right(Instr::OUTA), Left(1), Right(Instr::ADDA), Left(6), Right(Instr::CALL)];
+            let mut vm = VM::new(program);
+            assert_eq!(vm.step(), Ok(StepResult::Branched { to: 6 }));
+            assert_eq!((vm.SP, vm.dump()[255]), (254, 2));
+            vm.execute().unwrap();
+            assert_eq!(vm.output(), &[42]);
+            assert_eq!((vm.B, vm.SP), (9, 255));
+      }
 
-            vm = VM::new(set_y);
-            vm.execute();
-            assert_eq!(vm.Y, 40);
-      } 
-}
\ No newline at end of file
+      #[test]
+      fn tick_hook_interrupts_infinite_loops() {
+            // 0: ADDA 1, 2: JMP 0
+            let mut vm = VM::new(Program::new().add_a(1).jmp(0).build());
+            let mut ticks = 0;
+            vm.set_tick(10, move |vm| {
+                  ticks += 1;
+                  assert_eq!(vm.cycles(), ticks * 10);
+                  if ticks >= 3 { ControlFlow::Break(()) } else { ControlFlow::Continue(()) }
+            });
+
+            assert_eq!(vm.execute(), Err(VmError::Interrupted { cycles: 30 }));
+            assert_eq!(vm.A, 15);
+
+            // Execution resumes where it stopped
+            assert_eq!(vm.execute(), Err(VmError::Interrupted { cycles: 40 }));
+
+            let mut vm = VM::builder(Program::new().nop().build()).pc_overflow(PcOverflow::Wrap).build();
+            let seen = Rc::new(RefCell::new(Vec::new()));
+            let log = seen.clone();
+            vm.set_tick(1, move |vm| {
+                  log.borrow_mut().push(vm.cycles());
+                  if vm.cycles() >= 3 { ControlFlow::Break(()) } else { ControlFlow::Continue(()) }
+            });
+            assert_eq!(vm.execute(), Err(VmError::Interrupted { cycles: 3 }));
+            assert_eq!(*seen.borrow(), vec![1, 2, 3]);
+      }
+
+      #[test]
+      fn calling_subroutines_in_isolation() {
+            let (program, symbols) = assemble_with_symbols("HALT\n.include \"mul\"").unwrap();
+            let mut vm = VM::new(program);
+            vm.X = 6;
+            vm.Y = 7;
+            vm.call_subroutine(symbols["mul"]).unwrap();
+            assert_eq!((vm.A, vm.pc(), vm.SP), (42, 0, 255));
+
+            vm.Y = 3;
+            vm.X = 2;
+            vm.call_subroutine(symbols["mul"]).unwrap();
+            assert_eq!(vm.A, 6);
+            vm.execute().unwrap();
+            assert_eq!(vm.exit_code(), Some(0));
+
+            let (program, symbols) = assemble_with_symbols("HALT\nspin: JMP spin").unwrap();
+            let mut vm = VM::new(program);
+            assert_eq!(vm.call_subroutine(symbols["spin"]), Err(VmError::InstructionLimitExceeded));
+            assert!(vm.breakpoints.is_empty());
+      }
+
+      #[test]
+      fn checking_the_calling_convention() {
+            let (program, symbols) = assemble_with_symbols("HALT\n\
+                                                            keep: PUSHX\n\
+                                                            SETX #9\n\
+                                                            MOV A, X\n\
+                                                            POPX\n\
+                                                            RET\n\
+                                                            clobber: SETY #1\n\
+                                                            RET\n\
+                                                            spin: SETX #0\n\
+                                                            JMP spin").unwrap();
+            let mut vm = VM::new(program);
+            vm.X = 4;
+            vm.call_with_convention(symbols["keep"]).unwrap();
+            assert_eq!((vm.A, vm.X), (9, 4));
+
+            let err = vm.call_with_convention(symbols["clobber"]).unwrap_err();
+            assert_eq!(err, VmError::ConventionViolation { reg: Reg::Y });
+            assert_eq!(err.to_string(), "ConventionViolation: routine did not preserve Y");
+
+            assert_eq!(vm.call_with_convention(symbols["spin"]), Err(VmError::InstructionLimitExceeded));
+      }
+
+      #[test]
+      fn running_to_an_opcode() {
+            let program = Program::new().set_a(2).add_a(3).call(7).halt().add_b(1).ret().build();
+            let mut vm = VM::new(program);
+            vm.run_to_opcode(Instr::CALL).unwrap();
+            assert_eq!((vm.A, vm.pc()), (5, 4));
+            vm.run_to_opcode(Instr::CALL).unwrap();
+            assert_eq!(vm.pc(), 4);
+
+            vm.run_to_opcode(Instr::HALT).unwrap();
+            assert_eq!((vm.B, vm.pc(), vm.halt_reason()), (1, 6, None));
+            vm.step().unwrap();
+            assert_eq!(vm.halt_reason(), Some(HaltReason::Instruction));
+
+            let mut vm = VM::new(Program::new().jmp(0).build());
+            assert_eq!(vm.run_to_opcode(Instr::HALT), Err(VmError::InstructionLimitExceeded));
+      }
+
+      #[test]
+      fn trapping_arithmetic_faults() {
+            let (program, symbols) = assemble_with_symbols("SETA #7\n\
+                                                            SETB #0\n\
+                                                            DIV\n\
+                                                            SETX #1\n\
+                                                            HALT\n\
+                                                            handler: TFA\n\
+                                                            MOV Y, A\n\
+                                                            RET").unwrap();
+            let mut vm = VM::builder(program.clone()).arithmetic_trap(symbols["handler"]).build();
+            vm.step().unwrap();
+            vm.step().unwrap();
+            assert_eq!(vm.step(), Ok(StepResult::Branched { to: symbols["handler"] }));
+            vm.execute().unwrap();
+            assert_eq!((vm.X, vm.Y, vm.SP), (1, Flag::DIVZERO.code(), 255));
+
+            let mut vm = VM::new(program);
+            vm.execute().unwrap();
+            assert_eq!((vm.X, vm.Y, vm.CC.clone()), (1, 0, Flag::DIVZERO));
+
+            // A borrow past 0 is ordinary unsigned arithmetic
+            let (program, symbols) = assemble_with_symbols("SETA #0\n\
+                                                            SUBA #1\n\
+                                                            SETX #1\n\
+                                                            HALT\n\
+                                                            handler: SETY #9\n\
+                                                            RET").unwrap();
+            let mut vm = VM::builder(program).arithmetic_trap(symbols["handler"]).build();
+            vm.step().unwrap();
+            assert_eq!(vm.step(), Ok(StepResult::Continued));
+            vm.execute().unwrap();
+            assert_eq!((vm.X, vm.Y, vm.CC.clone()), (1, 0, Flag::CARRY));
+      }
+
+      #[test]
+      fn stepping_over_calls() {
+            // 0: CALL 5, 2: ADDA 1, 4: HALT, 5: SETB 2, 7: ADDB 3, 9: RET
+            let program = Program::new().call(5).add_a(1).halt().set_b(2).add_b(3).ret().build();
+
+            let mut vm = VM::new(program.clone());
+            assert_eq!(vm.step_over(), Ok(StepResult::Continued));
+            assert_eq!((vm.pc(), vm.A, vm.B, vm.SP), (2, 0, 5, 255));
+            assert_eq!(vm.step_over(), Ok(StepResult::Continued));
+            assert_eq!((vm.pc(), vm.A), (4, 1));
+
+            // Breakpoints inside the subroutine still stop it
+            vm = VM::new(program);
+            vm.add_breakpoint(7);
+            assert_eq!(vm.step_over(), Ok(StepResult::Hit(Hit::Breakpoint(7))));
+            assert_eq!(vm.breakpoints.iter().collect::<Vec<_>>(), vec![&7]);
+            assert_eq!(vm.step_over(), Ok(StepResult::Continued));
+            assert_eq!(vm.B, 5);
+
+            // 0: CALL 3, 2: HALT, 3: JMP 3
+            vm = VM::new(Program::new().call(3).halt().jmp(3).build());
+            assert_eq!(vm.step_over(), Err(VmError::InstructionLimitExceeded));
+            assert!(vm.breakpoints.is_empty());
+      }
+
+      #[test]
+      fn protecting_memory() {
+            // 0: LDA 0x20, 2: STA 0x21
+            let program = Program::new().lda(0x20).sta(0x21).halt().build();
+            let mut vm = VM::new(program);
+            vm.write_mem(0x20, 7).unwrap();
+            vm.protect(0x20..0x30, Perms::ReadOnly);
+
+            let err = vm.execute().unwrap_err();
+            assert_eq!(err, VmError::Context { pc: 2, instr: Instr::STA,
+                                               error: Box::new(VmError::ProtectionFault { addr: 0x21 }) });
+            assert_eq!((vm.A, vm.read_mem(0x21)), (7, Some(0)));
+
+            vm = VM::new(Program::new().push(1).halt().build());
+            vm.protect(0xF0..0x100, Perms::NoAccess);
+            assert_eq!(vm.execute().unwrap_err().cause(), &VmError::ProtectionFault { addr: 255 });
+      }
+
+      #[test]
+      fn adding_targets_each_register() {
+            let program = Program::new().set_a(1).set_b(2).set_x(3).set_y(4)
+                                        .add_a(10).add_b(20).add_x(30).add_y(40).halt().build();
+            let registers = run(program).unwrap();
+            assert_eq!((registers.A, registers.B, registers.X, registers.Y), (11, 22, 33, 44));
+
+            for (instr, reg) in [(Instr::ADDA, Reg::A), (Instr::ADDB, Reg::B), (Instr::ADDX, Reg::X), (Instr::ADDY, Reg::Y)].iter() {
+                  assert_eq!(instr.register(), Some(*reg));
+            }
+            assert_eq!(Instr::MOV.register(), None);
+      }
+
+      #[test]
+      fn per_call_budgets() {
+            // 0: CALL 5, 2: CALL 10, 4: HALT, 5: SETB 2, 7: ADDB 3, 9: RET, 10: ADDA 1, 12: JMP 10
+            let program = Program::new().call(5).call(10).halt().set_b(2).add_b(3).ret()
+                                        .add_a(1).jmp(10).build();
+            let mut vm = VM::builder(program).call_budget(8).build();
+
+            assert_eq!(vm.execute(), Err(VmError::CallBudgetExceeded));
+            assert_eq!((vm.B, vm.A), (5, 4));
+            assert_eq!(vm.pc(), 10);
+      }
+
+      #[test]
+      fn interrupts_keep_call_budgets() {
+            let (program, symbols) = assemble_with_symbols("SETA #254\n\
+                                                            STA 0xE0\n\
+                                                            CALL sub\n\
+                                                            HALT\n\
+                                                            sub: NOP\nNOP\nNOP\nNOP\nNOP\nNOP\n\
+                                                            RET\n\
+                                                            tick: RET").unwrap();
+            let mut vm = VM::builder(program).call_budget(4).interrupt_handler(symbols["tick"]).build();
+            vm.attach_device(0xE0..0xE1, Box::new(Timer::new(1).interrupt_on_wrap()));
+            vm.step().unwrap();
+            vm.step().unwrap();
+            // The timer wraps as CALL runs, so the handler runs before `sub`
+            assert_eq!(vm.step(), Ok(StepResult::Branched { to: symbols["tick"] }));
+            assert_eq!(vm.execute(), Err(VmError::CallBudgetExceeded));
+            assert_eq!(vm.pc(), symbols["sub"] + 3);
+      }
+
+      #[test]
+      fn arithmetic_with_memory_operands() {
+            let program = Program::new().set_a(10).add_a_mem(0x40).sub_a_mem(0x41).cmp_a_mem(0x42).halt().build();
+            let mut vm = VM::new(program);
+            vm.write_mem(0x40, 7).unwrap();
+            vm.write_mem(0x41, 2).unwrap();
+            vm.write_mem(0x42, 15).unwrap();
+            vm.execute().unwrap();
+            assert_eq!((vm.A, vm.CC.clone()), (15, Flag::ZERO));
+      }
+
+      #[test]
+      fn restoring_checks_invariants() {
+            let mut vm = VM::new(Program::new().set_a(1).push_a().halt().build());
+            assert_eq!(vm.check_invariants(), Ok(()));
+            vm.step().unwrap();
+            let snapshot = vm.snapshot();
+            vm.execute().unwrap();
+
+            vm.restore(&snapshot).unwrap();
+            assert_eq!((vm.pc(), vm.SP), (2, 255));
+            vm.execute().unwrap();
+            assert_eq!(vm.SP, 254);
+
+            let mut corrupt = snapshot.clone();
+            corrupt.registers.SP = 300;
+            assert_eq!(vm.restore(&corrupt), Err(VmError::InvalidState("stack pointer outside memory")));
+            corrupt = snapshot;
+            corrupt.pc = 99;
+            assert_eq!(vm.restore(&corrupt), Err(VmError::InvalidState("program counter outside program")));
+            assert_eq!((vm.pc(), vm.SP), (4, 254));
+      }
+
+      #[test]
+      fn restoring_inside_a_call() {
+            // 0: CALL 3, 2: HALT, 3: ADDA 1, 5: JMP 3
+            let program = Program::new().call(3).halt().add_a(1).jmp(3).build();
+            let mut vm = VM::builder(program).call_budget(4).build();
+            vm.step().unwrap();
+            vm.step().unwrap();
+            let snapshot = vm.snapshot();
+            assert_eq!((snapshot.cycles, snapshot.call_starts.clone()), (2, vec![1]));
+
+            assert_eq!(vm.execute(), Err(VmError::CallBudgetExceeded));
+            assert_eq!((vm.A, vm.cycles()), (2, 5));
+
+            // The budget runs from the restored frame, not the one that ran out
+            vm.restore(&snapshot).unwrap();
+            assert_eq!((vm.A, vm.cycles(), vm.exit_code), (1, 2, 0));
+            assert_eq!(vm.check_invariants(), Ok(()));
+            assert_eq!(vm.execute(), Err(VmError::CallBudgetExceeded));
+            assert_eq!((vm.A, vm.cycles()), (2, 5));
+      }
+
+      #[test]
+      fn fused_compare_and_branch() {
+            // 0: ADDA 1, 2: ADDB 3, 4: CBNE A, 5, 0, 8: CBEQ B, 15, 13, 12: OUTA, 13: HALT
+            let program = Program::new().add_a(1).add_b(3).cbne(0, 5, 0).cbeq(1, 15, 13).out_a().halt().build();
+            let mut vm = VM::new(program);
+            vm.execute().unwrap();
+            assert_eq!((vm.A, vm.B, vm.CC.clone()), (5, 15, Flag::DEFAULT));
+            assert!(vm.output().is_empty());
+
+            vm = VM::new(Program::new().cbeq(4, 0, 0).build());
+            assert_eq!(vm.execute().unwrap_err().cause(), &VmError::InvalidRegister(4));
+      }
+
+      #[test]
+      fn polling_a_memory_bit() {
+            // Reports ready on the third read
+            struct Status {
+                  reads: u8,
+            }
+            impl Device for Status {
+                  fn read(&mut self, _addr: usize) -> u8 {
+                        self.reads += 1;
+                        if self.reads >= 3 { 0x04 } else { 0 }
+                  }
+                  fn write(&mut self, _addr: usize, _val: u8) {}
+            }
+
+            let program = assemble("poll: ADDB #1\n\
+                                    BBS 0xE0, #2, ready\n\
+                                    JMP poll\n\
+                                    ready: BBC 0xE0, #2, poll\n\
+                                    HALT").unwrap();
+            let mut vm = VM::new(program);
+            vm.attach_device(0xE0..0xE1, Box::new(Status { reads: 0 }));
+            vm.execute().unwrap();
+            assert_eq!((vm.B, vm.CC.clone()), (3, Flag::DEFAULT));
+
+            vm = VM::new(Program::new().bbs(0x10, 8, 0).build());
+            assert_eq!(vm.execute().unwrap_err().cause(), &VmError::InvalidBit(8));
+      }
+}
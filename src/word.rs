@@ -0,0 +1,34 @@
+use std::fmt::Debug;
+
+// Element type of the VM's registers and memory. Program operands stay bytes
+// and are widened with `from_u8`; addresses and register values used as
+// addresses go through `to_usize`.
+pub trait Word: Copy + PartialEq + PartialOrd + Debug + 'static {
+      const ZERO: Self;
+      const MAX: Self;
+
+      fn from_u8(x: u8) -> Self;
+      // Truncates values that do not fit in the word
+      fn from_usize(x: usize) -> Self;
+      fn to_usize(self) -> usize;
+      fn overflowing_add(self, rhs: Self) -> (Self, bool);
+      fn overflowing_sub(self, rhs: Self) -> (Self, bool);
+}
+
+macro_rules! impl_word {
+      ($t:ty) => {
+            impl Word for $t {
+                  const ZERO: $t = 0;
+                  const MAX: $t = <$t>::MAX;
+
+                  fn from_u8(x: u8) -> $t { x as $t }
+                  fn from_usize(x: usize) -> $t { x as $t }
+                  fn to_usize(self) -> usize { self as usize }
+                  fn overflowing_add(self, rhs: $t) -> ($t, bool) { <$t>::overflowing_add(self, rhs) }
+                  fn overflowing_sub(self, rhs: $t) -> ($t, bool) { <$t>::overflowing_sub(self, rhs) }
+            }
+      };
+}
+
+impl_word!(u8);
+impl_word!(u16);